@@ -0,0 +1,156 @@
+//! Collision, target acquisition and bullet hits over a large crowd, every pair against the
+//! spatial grid. Run with `cargo run --release --features bench --example bench_crowd`.
+
+use std::time::Instant;
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use time_stabs::bench::{bullet_collision, collision, component, engage, index_units, Action, Bullet, BulletLog, Damage, SpatialGrid, State, TimeCursor, UNIT_HEALTH};
+
+const TICK_RATE: f32 = 64.0;
+const CROWD_UNITS: usize = 2000;
+const CROWD_BULLETS: usize = 500;
+const CROWD_TICKS: usize = 30;
+const CROWD_EXTENT: f32 = 3000.0;
+const CROWD_RADIUS: f32 = 20.0;
+const CROWD_RANGE: f32 = 500.0;
+const CROWD_BULLET_RADIUS: f32 = 5.0;
+const CROWD_BULLET_DAMAGE: i32 = 10;
+const CROWD_CELL_SIZE: f32 = 100.0;
+
+struct CrowdUnit {
+    owner: usize,
+    position: Vec2,
+    velocity: Vec2,
+}
+
+/// What the systems under test left behind in one tick, unit by unit in spawn order.
+#[derive(Default, Debug, PartialEq)]
+struct CrowdState {
+    positions: Vec<Vec2>,
+    targets: Vec<Option<Entity>>,
+    health: Vec<i32>,
+}
+
+impl CrowdState {
+    fn engagements(&self) -> usize {
+        self.targets.iter().filter(|target| target.is_some()).count()
+    }
+
+    fn hits(&self) -> usize {
+        self.health.iter().filter(|health| **health < UNIT_HEALTH).count()
+    }
+}
+
+/// A headless app running the game's own unit collision, target acquisition and bullet hit
+/// systems over the crowd. A grid with a single infinite cell hands every unit to every
+/// check, which is how they ran before the grid.
+fn crowd_app(cell_size: f32, units: &[CrowdUnit]) -> App {
+    let mut app = App::new();
+    app.insert_resource(SpatialGrid::new(cell_size))
+        .insert_resource(TimeCursor { current: 0.0, step: 0.0, scale: 1.0, paused: false })
+        .insert_resource(BulletLog { shots: Vec::new() })
+        .add_systems(Update, (
+                collision,
+                index_units,
+                engage,
+                bullet_collision,
+                ).chain());
+    for unit in units.iter() {
+        app.world.spawn((
+                component::Unit { owner: unit.owner },
+                Transform::from_translation(unit.position.extend(0.0)),
+                component::Radius { value: CROWD_RADIUS },
+                component::CurrentState { value: State::Idle },
+                component::CurrentAction { value: Action::None },
+                component::Target { entity: None, x: 0.0, y: 0.0 },
+                component::Attack { range: CROWD_RANGE, timer: Timer::from_seconds(1.0, TimerMode::Repeating) },
+                component::Health { current: UNIT_HEALTH, max: UNIT_HEALTH },
+                ));
+    }
+    app
+}
+
+/// Puts the crowd where `units` are, fires `bullets` into it and runs one update, returning
+/// how long the update took and what it left behind.
+fn crowd_tick(app: &mut App, units: &[CrowdUnit], bullets: &[Vec2]) -> (f64, CrowdState) {
+    let mut unit_query = app.world.query::<(Entity, &mut Transform, &mut component::CurrentState, &mut component::Target, &mut component::Health)>();
+    for (entity, mut transform, mut state, mut target, mut health) in unit_query.iter_mut(&mut app.world) {
+        transform.translation = units[entity.index() as usize].position.extend(0.0);
+        state.value = State::Idle;
+        target.entity = None;
+        health.current = UNIT_HEALTH;
+    }
+    let mut bullet_query = app.world.query_filtered::<Entity, With<Bullet>>();
+    let stale: Vec<Entity> = bullet_query.iter(&app.world).collect();
+    for entity in stale {
+        app.world.despawn(entity);
+    }
+    for position in bullets.iter() {
+        app.world.spawn((
+                Bullet { owner: 0, shot: None },
+                Damage { value: CROWD_BULLET_DAMAGE },
+                component::Radius { value: CROWD_BULLET_RADIUS },
+                Transform::from_translation(position.extend(0.0)),
+                ));
+    }
+
+    let start = Instant::now();
+    app.update();
+    let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+
+    let mut result = CrowdState::default();
+    let mut unit_query = app.world.query::<(Entity, &Transform, &component::Target, &component::Health)>();
+    let mut rows: Vec<_> = unit_query.iter(&app.world).collect();
+    rows.sort_by_key(|(entity, _, _, _)| *entity);
+    for (_, transform, target, health) in rows {
+        result.positions.push(transform.translation.xy());
+        result.targets.push(target.entity);
+        result.health.push(health.current);
+    }
+    (elapsed, result)
+}
+
+/// Two armies spread over the crowd area, each unit with a fixed heading.
+fn crowd_units(rng: &mut StdRng, count: usize) -> Vec<CrowdUnit> {
+    (0..count).map(|n| CrowdUnit {
+        owner: n % 2,
+        position: Vec2::new(rng.gen_range(0.0..CROWD_EXTENT), rng.gen_range(0.0..CROWD_EXTENT)),
+        velocity: Vec2::from_angle(rng.gen_range(0.0..std::f32::consts::TAU)) * (200.0 / TICK_RATE),
+    }).collect()
+}
+
+fn crowd_bullets(rng: &mut StdRng, count: usize) -> Vec<Vec2> {
+    (0..count)
+        .map(|_| Vec2::new(rng.gen_range(0.0..CROWD_EXTENT), rng.gen_range(0.0..CROWD_EXTENT)))
+        .collect()
+}
+
+fn move_crowd(units: &mut [CrowdUnit]) {
+    for unit in units.iter_mut() {
+        unit.position = (unit.position + unit.velocity).clamp(Vec2::ZERO, Vec2::splat(CROWD_EXTENT));
+    }
+}
+
+/// Runs the unit collision, target acquisition and bullet hit systems over a crowd of two
+/// armies and a spray of bullets, once with every pair checked and once through the spatial
+/// grid, and prints the time per tick of each.
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut units = crowd_units(&mut rng, CROWD_UNITS);
+    let mut naive_app = crowd_app(f32::INFINITY, &units);
+    let mut grid_app = crowd_app(CROWD_CELL_SIZE, &units);
+    println!("{} units, {} bullets, {} ticks", CROWD_UNITS, CROWD_BULLETS, CROWD_TICKS);
+    println!("{:>5} {:>10} {:>12} {:>6} {:>12} {:>12}", "tick", "naive ms", "grid ms", "same", "engagements", "hits");
+    let (mut naive_total, mut grid_total) = (0.0, 0.0);
+    for tick in 0..CROWD_TICKS {
+        let bullets = crowd_bullets(&mut rng, CROWD_BULLETS);
+        let (naive_ms, naive) = crowd_tick(&mut naive_app, &units, &bullets);
+        let (grid_ms, gridded) = crowd_tick(&mut grid_app, &units, &bullets);
+        naive_total += naive_ms;
+        grid_total += grid_ms;
+        println!("{:>5} {:>10.3} {:>12.3} {:>6} {:>12} {:>12}", tick + 1, naive_ms, grid_ms, naive == gridded, gridded.engagements(), gridded.hits());
+        move_crowd(&mut units);
+    }
+    println!("average: naive {:.3} ms, grid {:.3} ms, {:.1}x faster", naive_total / CROWD_TICKS as f64, grid_total / CROWD_TICKS as f64, naive_total / grid_total);
+}
@@ -0,0 +1,119 @@
+//! Memory held by snapshot histories over a few rounds of ghosts.
+//! Run with `cargo run --release --features bench --example bench_history`.
+
+use std::{collections::HashSet, mem::size_of, sync::Arc};
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use time_stabs::bench::{chunk_bytes, record, Action, HistoryBudget, Snapshot, SnapshotStore, State, HISTORY_BUDGET, UNIT_HEALTH};
+
+const UNITS: usize = 500;
+const ROUNDS: usize = 5;
+const TICK_RATE: f32 = 64.0;
+const TICKS_PER_ROUND: usize = 15 * 64;
+
+struct SimulatedUnit {
+    position: Vec3,
+    facing: f32,
+    state: State,
+    action: Action,
+    health: i32,
+    ticks_left: u32,
+    snapshots: SnapshotStore,
+}
+
+impl SimulatedUnit {
+    fn step(&mut self, rng: &mut StdRng) {
+        if self.health <= 0 {
+            self.state = State::Dead;
+            return;
+        }
+        if self.ticks_left == 0 {
+            self.ticks_left = rng.gen_range(30..200);
+            self.state = match rng.gen_range(0..3) {
+                0 => State::Idle,
+                1 => State::Move,
+                _ => State::Attack,
+            };
+            self.facing = rng.gen_range(0.0..std::f32::consts::TAU);
+        }
+        self.ticks_left -= 1;
+        self.action = Action::None;
+        match self.state {
+            State::Move => {
+                self.position += Vec3::new(self.facing.cos(), self.facing.sin(), 0.0) * (200.0 / TICK_RATE);
+            }
+            State::Attack => {
+                if self.ticks_left % 64 < 4 {
+                    self.action = Action::Attack;
+                }
+                if rng.gen_ratio(1, 200) {
+                    self.health -= 10;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn snapshot(&self, timestamp: f32) -> Snapshot {
+        Snapshot {
+            atlas_index: 0,
+            action: self.action,
+            state: self.state,
+            timestamp,
+            position: self.position,
+            facing: self.facing,
+            direction: 0.0,
+            health: self.health,
+            dead: self.health <= 0,
+        }
+    }
+}
+
+/// Records a few rounds of hundreds of simulated units, leaving a ghost clone of every unit
+/// behind each round, and prints how much memory the histories hold compared to storing
+/// every tick in its own copy.
+fn main() {
+    let budget = HistoryBudget { bytes: HISTORY_BUDGET };
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut units: Vec<SimulatedUnit> = (0..UNITS).map(|n| SimulatedUnit {
+        position: Vec3::new(n as f32 * 30.0, 0.0, 0.0),
+        facing: 0.0,
+        state: State::Idle,
+        action: Action::None,
+        health: UNIT_HEALTH,
+        ticks_left: 0,
+        snapshots: SnapshotStore::default(),
+    }).collect();
+    println!("snapshot: {} bytes, budget: {} bytes ({} snapshots) per unit", size_of::<Snapshot>(), budget.bytes, budget.snapshots());
+    println!("{:>5} {:>6} {:>7} {:>10} {:>10} {:>14} {:>14}", "round", "units", "ghosts", "recorded", "stored", "naive bytes", "stored bytes");
+    for round in 0..ROUNDS {
+        for tick in 0..TICKS_PER_ROUND {
+            let timestamp = (round * TICKS_PER_ROUND + tick) as f32 / TICK_RATE;
+            for unit in units.iter_mut() {
+                unit.step(&mut rng);
+                let snapshot = unit.snapshot(timestamp);
+                record(&mut unit.snapshots, budget.snapshots(), snapshot);
+            }
+        }
+        // Last round's ghosts have finished their playback, every unit leaves a new one behind.
+        let ghosts: Vec<SnapshotStore> = units.iter().map(|unit| unit.snapshots.clone()).collect();
+
+        let recorded = UNITS * (round + 1) * TICKS_PER_ROUND;
+        // One snapshot per tick for every unit plus a full copy for every ghost.
+        let naive_bytes = 2 * recorded * size_of::<Snapshot>();
+        let mut chunks = HashSet::new();
+        let mut stored = 0;
+        let mut stored_bytes = 0;
+        for store in units.iter().map(|unit| &unit.snapshots).chain(ghosts.iter()) {
+            stored += store.len();
+            stored_bytes += store.open_bytes();
+            for chunk in store.chunks() {
+                if chunks.insert(Arc::as_ptr(chunk)) {
+                    stored_bytes += chunk_bytes();
+                }
+            }
+        }
+        println!("{:>5} {:>6} {:>7} {:>10} {:>10} {:>14} {:>14}", round + 1, UNITS, ghosts.len(), recorded, stored, naive_bytes, stored_bytes);
+    }
+}
@@ -0,0 +1,6 @@
+//! The game's own systems and history storage, as driven by the benchmarks in `examples/`.
+
+pub use crate::bullet::{collision::collision as bullet_collision, component::{Bullet, Damage}, BulletLog};
+pub use crate::game::TimeCursor;
+pub use crate::map::spatial::SpatialGrid;
+pub use crate::unit::{action::{engage, Action}, collision::{collision, index_units}, component, history::{record, Snapshot}, store::{chunk_bytes, SnapshotStore}, HistoryBudget, State, HISTORY_BUDGET, UNIT_HEALTH};
@@ -1,18 +1,30 @@
 use bevy::prelude::*;
 
-use super::component::{Bullet, Damage};
+use super::{component::{Bullet, Damage}, BulletLog};
+use crate::game::TimeCursor;
+use crate::map::spatial::SpatialGrid;
 use crate::unit::component;
 
 pub fn collision (
     mut commands: Commands,
-    bullet_query: Query<(Entity, &Transform, &component::Radius, &Damage), With<Bullet>>,
-    mut unit_query: Query<(&Transform, &component::Radius, &mut component::Health), (With<component::Unit>, Without<component::Dead>)>,
+    bullet_query: Query<(Entity, &Bullet, &Transform, &component::Radius, &Damage)>,
+    mut unit_query: Query<(Entity, &Transform, &component::Radius, &mut component::Health), (With<component::Unit>, Without<component::Dead>)>,
+    mut bullet_log: ResMut<BulletLog>,
+    grid: Res<SpatialGrid>,
+    cursor: Res<TimeCursor>,
     ) {
-    for (bullet, bullet_transform, bullet_radius, damage) in bullet_query.iter() {
-        for (unit_transform, unit_radius, mut health) in unit_query.iter_mut() {
+    for (bullet, bullet_info, bullet_transform, bullet_radius, damage) in bullet_query.iter() {
+        for (candidate, _) in grid.nearby(bullet_transform.translation.xy(), bullet_radius.value + grid.max_radius) {
+            let Ok((unit, unit_transform, unit_radius, mut health)) = unit_query.get_mut(candidate) else {
+                continue;
+            };
             let distance = bullet_transform.translation.xy().distance(unit_transform.translation.xy());
             if distance < bullet_radius.value + unit_radius.value {
                 health.current -= damage.value;
+                if let Some(shot) = bullet_info.shot.and_then(|index| bullet_log.shots.get_mut(index)) {
+                    shot.ended = Some(cursor.current);
+                    shot.hits.push((unit, damage.value));
+                }
                 commands.entity(bullet).despawn();
             }
         }
@@ -3,9 +3,15 @@ use bevy::prelude::*;
 #[derive(Component)]
 pub struct Bullet {
     pub owner: usize,
+    pub shot: Option<usize>,
 }
 
 #[derive(Component)]
 pub struct Damage {
     pub value: i32,
 }
+
+#[derive(Component)]
+pub struct Lifetime {
+    pub timer: Timer,
+}
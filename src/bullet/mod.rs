@@ -1,16 +1,74 @@
+use std::f32::consts::PI;
 use bevy::{prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}};
-use crate::unit::component::{Velocity, MoveSpeed, Radius};
+use crate::game::{Seek, TimeCursor};
+use crate::unit::component::{CurrentState, Dead, Health, Velocity, MoveSpeed, Radius};
+use crate::unit::{health::revive, State};
+use crate::AppState;
 
-mod collision;
-mod component;
+pub(crate) mod collision;
+pub(crate) mod component;
 mod movement;
 
 const BULLET_SPEED: f32 = 500.0;
 const BULLET_RADIUS: f32 = 5.0;
 const BULLET_COLOR: Color = Color::rgb(1.0, 0.0, 0.0);
+const BULLET_DAMAGE: i32 = 10;
+const RECALL_DISTANCE: f32 = 150.0;
 
 #[derive(Event)]
-pub struct Fire(pub usize,pub Vec2, pub f32);
+pub struct Fire(pub Entity, pub usize, pub Vec2, pub f32);
+
+/// Pulls a bullet back into the muzzle. With a logged shot it retraces that shot's path and
+/// undoes its damage, otherwise it flies in from a fixed distance.
+#[derive(Event)]
+pub struct Recall(pub usize, pub Vec2, pub f32, pub Option<usize>);
+
+/// A bullet fired this round. Bullets fly straight at a constant speed, so where it left the
+/// muzzle, when, and when it stopped describe its whole path.
+pub struct Shot {
+    pub shooter: Entity,
+    pub owner: usize,
+    pub origin: Vec2,
+    pub rotation: f32,
+    pub fired: f32,
+    pub ended: Option<f32>,
+    pub hits: Vec<(Entity, i32)>,
+}
+
+impl Shot {
+    pub fn position(&self, moment: f32) -> Vec2 {
+        self.origin + self.forward() * BULLET_SPEED * (moment - self.fired).max(0.0)
+    }
+
+    fn forward(&self) -> Vec2 {
+        (Quat::from_rotation_z(self.rotation) * Vec3::Y).truncate()
+    }
+}
+
+#[derive(Resource)]
+pub struct BulletLog {
+    pub shots: Vec<Shot>,
+}
+
+impl BulletLog {
+    /// Shots by `shooter` that stopped in `(from, to]`, still flying ones counting as
+    /// stopped at `now`.
+    pub fn ended_between(&self, shooter: Entity, from: f32, to: f32, now: f32) -> Vec<usize> {
+        self.shots.iter()
+            .enumerate()
+            .filter(|(_, shot)| shot.shooter == shooter)
+            .filter(|(_, shot)| {
+                let ended = shot.ended.unwrap_or(now);
+                ended > from && ended <= to
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn fired_by(&self, shooter: Entity) -> bool {
+        self.shots.iter().any(|shot| shot.shooter == shooter)
+    }
+}
 
 pub struct BulletPlugin;
 
@@ -18,10 +76,16 @@ impl Plugin for BulletPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Update, (
                 fire,
+                recall,
+                expire,
+                rewind,
                 movement::calculate_and_apply_velocity,
-                collision::collision
+                collision::collision.after(movement::calculate_and_apply_velocity).after(crate::unit::collision::index_units)
                 ))
-            .add_event::<Fire>();
+            .add_systems(OnEnter(AppState::RoundStart), clear_log)
+            .insert_resource(BulletLog { shots: Vec::new() })
+            .add_event::<Fire>()
+            .add_event::<Recall>();
     }
 }
 
@@ -30,22 +94,160 @@ pub fn fire(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
     mut reader: EventReader<Fire>,
+    mut bullet_log: ResMut<BulletLog>,
+    cursor: Res<TimeCursor>,
     ) {
     for event in reader.read() {
-        let mut spawn_transform = Transform::from_xyz(event.1.x, event.1.y, 0.0);
-        spawn_transform.rotate_z(event.2);
+        let mut spawn_transform = Transform::from_xyz(event.2.x, event.2.y, 0.0);
+        spawn_transform.rotate_z(event.3);
         let forward = (spawn_transform.rotation * Vec3::Y).truncate();
+        bullet_log.shots.push(Shot {
+            shooter: event.0,
+            owner: event.1,
+            origin: event.2,
+            rotation: event.3,
+            fired: cursor.current,
+            ended: None,
+            hits: Vec::new(),
+        });
         commands.spawn((MaterialMesh2dBundle {
             mesh: Mesh2dHandle(meshes.add(Circle { radius: BULLET_RADIUS })),
             material: materials.add(BULLET_COLOR),
             transform: spawn_transform,
             ..default()
         },
-        component::Bullet { owner: event.0 },
+        component::Bullet { owner: event.1, shot: Some(bullet_log.shots.len() - 1) },
         Radius { value: BULLET_RADIUS },
-        component::Damage { value: 10 },
+        component::Damage { value: BULLET_DAMAGE },
         Velocity { x: forward.x, y: forward.y },
         MoveSpeed { value: BULLET_SPEED },
         ));
     }
 }
+
+pub fn recall(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut reader: EventReader<Recall>,
+    mut bullet_log: ResMut<BulletLog>,
+    asset_server: Res<AssetServer>,
+    bullet_query: Query<(Entity, &component::Bullet)>,
+    mut health_query: Query<(Entity, &mut Health, &mut CurrentState, Has<Dead>)>,
+    cursor: Res<TimeCursor>,
+    ) {
+    for event in reader.read() {
+        // A shot still in flight is taken out of the air rather than left to hit something.
+        if let Some(index) = event.3 {
+            for (bullet, bullet_info) in bullet_query.iter() {
+                if bullet_info.shot == Some(index) {
+                    commands.entity(bullet).despawn();
+                }
+            }
+        }
+        let mut muzzle = event.1;
+        let mut rotation = event.2;
+        let mut distance = RECALL_DISTANCE;
+        if let Some(shot) = event.3.and_then(|index| bullet_log.shots.get_mut(index)) {
+            muzzle = shot.origin;
+            rotation = shot.rotation;
+            distance = shot.position(shot.ended.unwrap_or(cursor.current)).distance(shot.origin);
+            // The bullet is pulled back out of whatever it hit.
+            for (victim, damage) in shot.hits.drain(..) {
+                if let Ok((entity, mut health, mut state, dead)) = health_query.get_mut(victim) {
+                    health.current = (health.current + damage).min(health.max);
+                    if dead && health.current > 0 {
+                        state.value = State::Idle;
+                        revive(&mut commands, entity, &asset_server, &mut meshes, &mut materials);
+                    }
+                }
+            }
+        }
+        let mut muzzle_transform = Transform::from_xyz(muzzle.x, muzzle.y, 0.0);
+        muzzle_transform.rotate_z(rotation);
+        let forward = (muzzle_transform.rotation * Vec3::Y).truncate();
+        let start = muzzle + forward * distance;
+        let mut spawn_transform = Transform::from_xyz(start.x, start.y, 0.0);
+        spawn_transform.rotate_z(rotation + PI);
+        commands.spawn((MaterialMesh2dBundle {
+            mesh: Mesh2dHandle(meshes.add(Circle { radius: BULLET_RADIUS })),
+            material: materials.add(BULLET_COLOR),
+            transform: spawn_transform,
+            ..default()
+        },
+        component::Bullet { owner: event.0, shot: None },
+        component::Lifetime { timer: Timer::from_seconds(distance / BULLET_SPEED, TimerMode::Once) },
+        Radius { value: BULLET_RADIUS },
+        Velocity { x: -forward.x, y: -forward.y },
+        MoveSpeed { value: BULLET_SPEED },
+        ));
+    }
+}
+
+pub fn expire(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut component::Lifetime)>,
+    time: Res<Time>,
+    ) {
+    for (entity, mut lifetime) in query.iter_mut() {
+        lifetime.timer.tick(time.delta());
+        if lifetime.timer.finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Puts every bullet back where it was at the seeked moment. Shots fired later are forgotten
+/// and shots that stopped later fly again; their damage is undone by the unit's own history.
+pub fn rewind(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut seek_reader: EventReader<Seek>,
+    mut bullet_log: ResMut<BulletLog>,
+    query: Query<Entity, With<component::Bullet>>,
+    ) {
+    for event in seek_reader.read() {
+        let moment = event.0;
+        for entity in query.iter() {
+            commands.entity(entity).despawn();
+        }
+        bullet_log.shots.retain(|shot| shot.fired <= moment);
+        for (index, shot) in bullet_log.shots.iter_mut().enumerate() {
+            if shot.ended.is_some_and(|ended| ended <= moment) {
+                continue;
+            }
+            shot.ended = None;
+            shot.hits.clear();
+            let position = shot.position(moment);
+            let forward = shot.forward();
+            let mut spawn_transform = Transform::from_xyz(position.x, position.y, 0.0);
+            spawn_transform.rotate_z(shot.rotation);
+            commands.spawn((MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(Circle { radius: BULLET_RADIUS })),
+                material: materials.add(BULLET_COLOR),
+                transform: spawn_transform,
+                ..default()
+            },
+            component::Bullet { owner: shot.owner, shot: Some(index) },
+            Radius { value: BULLET_RADIUS },
+            component::Damage { value: BULLET_DAMAGE },
+            Velocity { x: forward.x, y: forward.y },
+            MoveSpeed { value: BULLET_SPEED },
+            ));
+        }
+    }
+}
+
+/// Forgets last round's shots. Bullets still flying go with them, their indices would point
+/// into the new round's log.
+pub fn clear_log(
+    mut commands: Commands,
+    mut bullet_log: ResMut<BulletLog>,
+    query: Query<Entity, With<component::Bullet>>,
+    ) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    bullet_log.shots.clear();
+}
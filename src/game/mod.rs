@@ -9,6 +9,23 @@ pub struct Round {
     pub attempts: u32,
 }
 
+/// Where the round is in time, counted from its start. It only advances in
+/// `AppState::InGame` and drives `Time<Virtual>`, so every `Res<Time>` outside of input
+/// handling follows its pause and scale. `Seek` moves it to another round time.
+#[derive(Resource)]
+pub struct TimeCursor {
+    pub current: f32,
+    /// Round time of the current fixed step, what anything recorded in `FixedUpdate` is
+    /// stamped with. Frames can run several fixed steps or none at all.
+    pub step: f32,
+    pub scale: f32,
+    pub paused: bool,
+}
+
+/// Moves the `TimeCursor` to a round time, rewinding every unit and bullet with it.
+#[derive(Event)]
+pub struct Seek(pub f32);
+
 const ROUND_DURATION: f32 = 15.0;
 
 pub struct GamePlugin;
@@ -19,7 +36,17 @@ impl Plugin for GamePlugin {
             timer: Timer::from_seconds(ROUND_DURATION, TimerMode::Once),
             attempts: 0,
         })
+        .insert_resource(TimeCursor {
+            current: 0.0,
+            step: 0.0,
+            scale: 1.0,
+            paused: false,
+        })
+        .add_event::<Seek>()
         .add_systems(OnEnter(AppState::RoundStart), start_round)
+        .add_systems(OnEnter(AppState::InGame), start_cursor)
+        .add_systems(PreUpdate, tick_cursor)
+        .add_systems(FixedFirst, step_cursor.run_if(in_state(AppState::InGame)))
         .add_systems(Update, count_round_time.run_if(in_state(AppState::InGame)))
         .add_systems(Update, end_round.run_if(in_state(AppState::InGame)));
     }
@@ -32,6 +59,37 @@ fn start_round (
     round.attempts += 1;
 }
 
+fn start_cursor (
+    mut cursor: ResMut<TimeCursor>,
+    ) {
+    cursor.current = 0.0;
+    cursor.step = 0.0;
+}
+
+fn tick_cursor (
+    mut cursor: ResMut<TimeCursor>,
+    mut time: ResMut<Time<Virtual>>,
+    app_state: Res<State<AppState>>,
+    ) {
+    if *app_state.get() == AppState::InGame {
+        cursor.current += time.delta_seconds();
+    }
+    // Takes effect from the next frame on, fixed steps included.
+    if cursor.paused || *app_state.get() != AppState::InGame {
+        time.pause();
+    } else {
+        time.unpause();
+    }
+    time.set_relative_speed(cursor.scale);
+}
+
+fn step_cursor (
+    mut cursor: ResMut<TimeCursor>,
+    time: Res<Time>,
+    ) {
+    cursor.step += time.delta_seconds();
+}
+
 fn count_round_time (
     time: Res<Time>,
     mut round: ResMut<Round>,
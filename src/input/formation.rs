@@ -0,0 +1,58 @@
+use bevy::prelude::*;
+
+use super::Formation;
+
+const SLOT_SPACING: f32 = 50.0;
+
+/// Slot targets for a group ordered to `point`, one per unit and in the same order. The group
+/// faces from its centre towards the click; units are matched to slots side to side, front to
+/// back, so their paths cross as little as possible.
+pub fn slots(formation: Formation, units: &[(Entity, Vec2)], point: Vec2) -> Vec<Vec2> {
+    if units.len() < 2 || formation == Formation::Point {
+        return vec![point; units.len()];
+    }
+    let centre = units.iter().map(|(_, position)| *position).sum::<Vec2>() / units.len() as f32;
+    if formation == Formation::Relative {
+        return units.iter().map(|(_, position)| point + (*position - centre)).collect();
+    }
+    let forward = (point - centre).try_normalize().unwrap_or(Vec2::Y);
+    let right = Vec2::new(forward.y, -forward.x);
+    let offsets: Vec<Vec2> = (0..units.len()).map(|index| offset(formation, index, units.len())).collect();
+
+    // Both sides sorted by (sideways, forwards), then paired off in order.
+    let local = |position: Vec2| Vec2::new((position - centre).dot(right), (position - centre).dot(forward));
+    let mut unit_order: Vec<usize> = (0..units.len()).collect();
+    unit_order.sort_by(|a, b| {
+        let (a_local, b_local) = (local(units[*a].1), local(units[*b].1));
+        a_local.x.total_cmp(&b_local.x)
+            .then(b_local.y.total_cmp(&a_local.y))
+            .then(units[*a].0.cmp(&units[*b].0))
+    });
+    let mut slot_order: Vec<usize> = (0..offsets.len()).collect();
+    slot_order.sort_by(|a, b| {
+        offsets[*a].x.total_cmp(&offsets[*b].x)
+            .then(offsets[*b].y.total_cmp(&offsets[*a].y))
+            .then(a.cmp(b))
+    });
+
+    let mut slots = vec![point; units.len()];
+    for (unit, slot) in unit_order.iter().zip(slot_order.iter()) {
+        slots[*unit] = point + right * offsets[*slot].x + forward * offsets[*slot].y;
+    }
+    slots
+}
+
+/// Slot `index` of `count` as (sideways, forwards) from the click point.
+fn offset(formation: Formation, index: usize, count: usize) -> Vec2 {
+    match formation {
+        Formation::Line => Vec2::new((index as f32 - (count - 1) as f32 / 2.0) * SLOT_SPACING, 0.0),
+        Formation::Column => Vec2::new(0.0, -(index as f32) * SLOT_SPACING),
+        Formation::Wedge => {
+            // The tip on the click, then alternating left and right one row further back.
+            let row = index.div_ceil(2) as f32;
+            let side = if index % 2 == 1 { -1.0 } else { 1.0 };
+            Vec2::new(side * row * SLOT_SPACING, -row * SLOT_SPACING)
+        }
+        Formation::Point | Formation::Relative => Vec2::ZERO,
+    }
+}
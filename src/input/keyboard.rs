@@ -1,7 +1,8 @@
 use bevy::{input::{keyboard::KeyboardInput, ButtonState}, prelude::*};
 
-use super::{component::{Selectable, Selected}, ControlGroups, Repeat, Reverse};
-use crate::unit::State;
+use super::{component::{Selectable, Selected}, CompareBranch, ControlGroups, Export, Formation, Import, PendingOrder, Repeat, Reverse, SelectBranch};
+use crate::game::{Seek, TimeCursor};
+use crate::unit::{ChronoEnergy, GhostGenerations, HistoryMode, ParadoxRule, Refused, ReverseFire, State};
 use crate::unit::component;
 
 const UP: KeyCode = KeyCode::KeyW;
@@ -10,14 +11,41 @@ const LEFT: KeyCode = KeyCode::KeyA;
 const RIGHT: KeyCode = KeyCode::KeyD;
 const ATTACK: KeyCode = KeyCode::KeyE;
 const STOP: KeyCode = KeyCode::KeyQ;
+const ATTACK_MOVE: KeyCode = KeyCode::KeyT;
+const HOLD: KeyCode = KeyCode::KeyH;
+const PATROL: KeyCode = KeyCode::KeyR;
+const FORMATION: KeyCode = KeyCode::KeyG;
 const SHIFT: KeyCode = KeyCode::ShiftLeft;
 const CONTROL: KeyCode = KeyCode::ControlLeft;
 const CANCEL: KeyCode = KeyCode::Escape;
+const REWIND: KeyCode = KeyCode::BracketLeft;
+const RESTART: KeyCode = KeyCode::Home;
+const EXPORT: KeyCode = KeyCode::F5;
+const IMPORT: KeyCode = KeyCode::F9;
+const PREVIOUS_BRANCH: KeyCode = KeyCode::Comma;
+const NEXT_BRANCH: KeyCode = KeyCode::Period;
+const COMPARE_BRANCH: KeyCode = KeyCode::Slash;
+const TOGGLE_HISTORY_MODE: KeyCode = KeyCode::F1;
+const TOGGLE_REVERSE_FIRE: KeyCode = KeyCode::F2;
+const TOGGLE_PARADOX_RULE: KeyCode = KeyCode::F3;
+const SLOWER: KeyCode = KeyCode::Minus;
+const FASTER: KeyCode = KeyCode::Equal;
+const PAUSE_PLAYBACK: KeyCode = KeyCode::KeyP;
+const LOOP: KeyCode = KeyCode::KeyL;
+const PAUSE_CLOCK: KeyCode = KeyCode::Space;
+const SLOW_CLOCK: KeyCode = KeyCode::PageDown;
+const SPEED_CLOCK: KeyCode = KeyCode::PageUp;
+const LOOP_COUNT: u32 = 3;
+const SEEK_STEP: f32 = 1.0;
+const MIN_PLAYBACK_RATE: f32 = 0.125;
+const MAX_PLAYBACK_RATE: f32 = 8.0;
+const MIN_CLOCK_SCALE: f32 = 0.25;
+const MAX_CLOCK_SCALE: f32 = 4.0;
 
 pub fn camera_movement(
     mut query: Query<(&Camera, &mut Transform)>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
-    time: Res<Time>,
+    time: Res<Time<Real>>,
     ) {
     for (_, mut transform) in query.iter_mut() {
         let mut direction = Vec3::new(0.0, 0.0, 0.0);
@@ -52,37 +80,235 @@ pub fn shift_input(
 pub fn control_input(
     mut reverse_writer: EventWriter<Reverse>,
     mut repeat_writer: EventWriter<Repeat>,
-    query: Query<Entity, (With<component::Unit>, With<Selected>, With<component::History>)>,
+    mut refused_writer: EventWriter<Refused>,
+    query: Query<(Entity, &component::Unit, &component::History, &component::CommandLog, Option<&component::Lineage>, Has<component::Ghost>, Has<component::Echo>), With<Selected>>,
+    mut energy: ResMut<ChronoEnergy>,
+    generations: Res<GhostGenerations>,
+    history_mode: Res<HistoryMode>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
     ) {
-    if keyboard_input.just_pressed(UP) {
-        if !keyboard_input.pressed(SHIFT){
-            for entity in query.iter() {
-                repeat_writer.send(Repeat(entity, false));
+    if !keyboard_input.just_pressed(UP) && !keyboard_input.just_pressed(CANCEL) {
+        return;
+    }
+    let clone = keyboard_input.pressed(SHIFT);
+    let repeat = keyboard_input.just_pressed(UP);
+    for (entity, unit, history, log, opt_lineage, ghost, echo) in query.iter() {
+        // A ghost already playing back can only be cloned, never turned into a ghost again.
+        if !clone && (ghost || echo) {
+            continue;
+        }
+        // Only pay for commands the start systems will actually carry out.
+        let seconds = match *history_mode {
+            HistoryMode::Snapshot => {
+                if history.snapshots.is_empty() {
+                    continue;
+                }
+                history.seconds()
+            },
+            HistoryMode::Command => {
+                if !repeat {
+                    warn!("Can't reverse {:?}, a command log only plays forwards", entity);
+                    continue;
+                }
+                if let None = log.origin {
+                    continue;
+                }
+                log.seconds()
+            },
+        };
+        // Clones pay for the generation they add on top of the history they replay.
+        let mut generation_cost = 1.0;
+        if clone {
+            let generation = opt_lineage.map_or(0, |lineage| lineage.generation()) + 1;
+            if generation > generations.max_depth {
+                warn!("Can't echo {:?}, generation {} is past the limit of {}", entity, generation, generations.max_depth);
+                continue;
             }
+            generation_cost = generations.cost(generation);
+        }
+        let cost = energy.cost(seconds, generation_cost);
+        if !energy.spend(unit.owner, cost) {
+            warn!("Not enough chrono energy for {:?}: needs {:.0}, has {:.0}", entity, cost, energy.available(unit.owner));
+            refused_writer.send(Refused(unit.owner, cost));
+            continue;
+        }
+        if repeat {
+            repeat_writer.send(Repeat(entity, clone));
         } else {
-            for entity in query.iter() {
-                repeat_writer.send(Repeat(entity, true));
-            }
+            reverse_writer.send(Reverse(entity, clone));
         }
     }
-    if keyboard_input.just_pressed(CANCEL) {
-        if !keyboard_input.pressed(SHIFT){
-            for entity in query.iter() {
-                reverse_writer.send(Reverse(entity, false));
+}
+
+pub fn playback_input(
+    mut query: Query<(Option<&mut component::Repeat>, Option<&mut component::Reverse>), (With<component::Ghost>, With<Selected>)>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    let mut scale = 1.0;
+    if keyboard_input.just_pressed(SLOWER) {
+        scale = 0.5;
+    }
+    if keyboard_input.just_pressed(FASTER) {
+        scale = 2.0;
+    }
+    let toggle_pause = keyboard_input.just_pressed(PAUSE_PLAYBACK);
+    if scale == 1.0 && !toggle_pause {
+        return;
+    }
+    for (opt_repeat, opt_reverse) in query.iter_mut() {
+        if let Some(mut repeat) = opt_repeat {
+            repeat.rate = (repeat.rate * scale).clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE);
+            if toggle_pause {
+                repeat.paused = !repeat.paused;
             }
-        } else {
-            for entity in query.iter() {
-                reverse_writer.send(Reverse(entity, true));
+        }
+        if let Some(mut reverse) = opt_reverse {
+            reverse.rate = (reverse.rate * scale).clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE);
+            if toggle_pause {
+                reverse.paused = !reverse.paused;
             }
         }
     }
 }
 
+pub fn loop_input(
+    mut commands: Commands,
+    query: Query<(Entity, Option<&component::Loop>), (With<component::Ghost>, With<component::Repeat>, With<Selected>)>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(LOOP) {
+        for (entity, opt_loop) in query.iter() {
+            if let Some(_) = opt_loop {
+                commands.entity(entity).remove::<component::Loop>();
+            } else if keyboard_input.pressed(SHIFT) {
+                commands.entity(entity).insert(component::Loop { remaining: Some(LOOP_COUNT) });
+            } else {
+                commands.entity(entity).insert(component::Loop { remaining: None });
+            }
+        }
+    }
+}
+
+pub fn seek_input(
+    mut seek_writer: EventWriter<Seek>,
+    cursor: Res<TimeCursor>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(REWIND) {
+        seek_writer.send(Seek((cursor.current - SEEK_STEP).max(0.0)));
+    }
+    if keyboard_input.just_pressed(RESTART) {
+        seek_writer.send(Seek(0.0));
+    }
+}
+
+pub fn recording_input(
+    mut export_writer: EventWriter<Export>,
+    mut import_writer: EventWriter<Import>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(EXPORT) {
+        export_writer.send(Export);
+    }
+    if keyboard_input.just_pressed(IMPORT) {
+        import_writer.send(Import);
+    }
+}
+
+pub fn branch_input(
+    mut select_writer: EventWriter<SelectBranch>,
+    mut compare_writer: EventWriter<CompareBranch>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(PREVIOUS_BRANCH) {
+        select_writer.send(SelectBranch(-1));
+    }
+    if keyboard_input.just_pressed(NEXT_BRANCH) {
+        select_writer.send(SelectBranch(1));
+    }
+    if keyboard_input.just_pressed(COMPARE_BRANCH) {
+        compare_writer.send(CompareBranch);
+    }
+}
+
+pub fn clock_input(
+    mut cursor: ResMut<TimeCursor>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(PAUSE_CLOCK) {
+        cursor.paused = !cursor.paused;
+        info!("Time cursor paused: {}", cursor.paused);
+    }
+    if keyboard_input.just_pressed(SLOW_CLOCK) {
+        cursor.scale = (cursor.scale * 0.5).max(MIN_CLOCK_SCALE);
+        info!("Time cursor scale: {}", cursor.scale);
+    }
+    if keyboard_input.just_pressed(SPEED_CLOCK) {
+        cursor.scale = (cursor.scale * 2.0).min(MAX_CLOCK_SCALE);
+        info!("Time cursor scale: {}", cursor.scale);
+    }
+}
+
+pub fn toggle_history_mode(
+    mut history_mode: ResMut<HistoryMode>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(TOGGLE_HISTORY_MODE) {
+        *history_mode = match *history_mode {
+            HistoryMode::Snapshot => HistoryMode::Command,
+            HistoryMode::Command => HistoryMode::Snapshot,
+        };
+        info!("History mode: {:?}", *history_mode);
+    }
+}
+
+pub fn cycle_formation(
+    mut formation: ResMut<Formation>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(FORMATION) {
+        *formation = match *formation {
+            Formation::Point => Formation::Line,
+            Formation::Line => Formation::Column,
+            Formation::Column => Formation::Wedge,
+            Formation::Wedge => Formation::Relative,
+            Formation::Relative => Formation::Point,
+        };
+        info!("Formation: {:?}", *formation);
+    }
+}
+
+pub fn toggle_reverse_fire(
+    mut reverse_fire: ResMut<ReverseFire>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(TOGGLE_REVERSE_FIRE) {
+        *reverse_fire = match *reverse_fire {
+            ReverseFire::Recall => ReverseFire::Ignore,
+            ReverseFire::Ignore => ReverseFire::Recall,
+        };
+        info!("Reverse fire: {:?}", *reverse_fire);
+    }
+}
+
+pub fn toggle_paradox_rule(
+    mut paradox_rule: ResMut<ParadoxRule>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(TOGGLE_PARADOX_RULE) {
+        *paradox_rule = match *paradox_rule {
+            ParadoxRule::Ignore => ParadoxRule::Collapse,
+            ParadoxRule::Collapse => ParadoxRule::FailRound,
+            ParadoxRule::FailRound => ParadoxRule::Ignore,
+        };
+        info!("Paradox rule: {:?}", *paradox_rule);
+    }
+}
+
 pub fn double_tap_timer(
     mut keyboard_event: EventReader<KeyboardInput>,
     mut timer: ResMut<super::DoubleTap>,
-    time: Res<Time>,
+    time: Res<Time<Real>>,
     ) {
     if timer.timer.finished() {
         for event in keyboard_event.read() {
@@ -98,7 +324,7 @@ pub fn double_tap_timer(
 
 pub fn shoot(
     mut do_writer: EventWriter<super::Do>,
-    mut query: Query<(Entity, &Transform), (With<component::Unit>, With<Selected>)>,
+    mut query: Query<(Entity, &Transform), (With<component::Unit>, With<Selected>, Without<component::Ghost>)>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
     ) {
     if keyboard_input.just_pressed(ATTACK) {
@@ -108,14 +334,41 @@ pub fn shoot(
     }
 }
 
+pub fn attack_move(
+    mut pending: ResMut<PendingOrder>,
+    query: Query<Entity, (With<component::Unit>, With<Selected>, Without<component::Ghost>)>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(ATTACK_MOVE) && !query.is_empty() {
+        pending.state = Some(State::AttackMove);
+    }
+}
+
+pub fn patrol(
+    mut pending: ResMut<PendingOrder>,
+    query: Query<Entity, (With<component::Unit>, With<Selected>, Without<component::Ghost>)>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    ) {
+    if keyboard_input.just_pressed(PATROL) && !query.is_empty() {
+        pending.state = Some(State::Patrol);
+    }
+}
+
 pub fn stop(
     mut do_writer: EventWriter<super::Do>,
-    mut query: Query<(Entity, &Transform), (With<component::Unit>, With<Selected>)>,
+    mut query: Query<(Entity, &Transform, &mut component::OrderQueue), (With<component::Unit>, With<Selected>, Without<component::Ghost>)>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
     ) {
     if keyboard_input.just_pressed(STOP) {
-        for (entity, _) in query.iter_mut() {
-            do_writer.send(super::Do(entity, State::Idle, Vec2::new(0.0, 0.0)));
+        for (entity, transform, mut queue) in query.iter_mut() {
+            queue.orders.clear();
+            do_writer.send(super::Do(entity, State::Stop, transform.translation.xy()));
+        }
+    }
+    if keyboard_input.just_pressed(HOLD) {
+        for (entity, transform, mut queue) in query.iter_mut() {
+            queue.orders.clear();
+            do_writer.send(super::Do(entity, State::Halt, transform.translation.xy()));
         }
     }
 }
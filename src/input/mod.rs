@@ -9,8 +9,9 @@ use crate::unit;
 use crate::AppState;
 
 pub mod component;
+mod formation;
 mod keyboard;
-mod mouse;
+pub(crate) mod mouse;
 
 #[derive(Event)]
 pub struct Select(Entity);
@@ -30,11 +31,41 @@ pub struct Repeat(pub Entity, pub bool);
 #[derive(Event)]
 pub struct Reverse(pub Entity, pub bool);
 
+#[derive(Event)]
+pub struct Export;
+
+#[derive(Event)]
+pub struct Import;
+
+#[derive(Event)]
+pub struct SelectBranch(pub i32);
+
+#[derive(Event)]
+pub struct CompareBranch;
+
 #[derive(Resource)]
 pub struct ControlGroups {
     pub groups: HashMap<KeyCode, Vec<Entity>>
 }
 
+/// An order armed from the keyboard, issued by the next click instead of a plain move.
+/// `swallow` keeps the issuing left click from also changing the selection.
+#[derive(Resource)]
+pub struct PendingOrder {
+    pub state: Option<unit::State>,
+    pub swallow: bool,
+}
+
+/// Shape a group of selected units moves into. `Point` sends them all to the click itself.
+#[derive(Resource, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Formation {
+    Point,
+    Line,
+    Column,
+    Wedge,
+    Relative,
+}
+
 #[derive(Resource)]
 pub struct DoubleClick {
     pub timer: Timer,
@@ -54,27 +85,51 @@ impl Plugin for InputPlugin {
             .add_systems(Update, (
                     mouse::double_click_timer.after(mouse::select_entities),
                     mouse::show_hide_box,
-                    mouse::select_entities,
+                    mouse::select_entities.after(mouse::act),
                     mouse::act,
                     keyboard::double_tap_timer.after(keyboard::get_control_group),
                     keyboard::camera_movement,
                     keyboard::shoot,
                     keyboard::stop,
+                    keyboard::attack_move,
                     keyboard::shift_input,
                     keyboard::control_input,
+                    keyboard::playback_input,
+                    keyboard::loop_input,
+                    keyboard::seek_input.run_if(in_state(AppState::InGame)),
+                    keyboard::recording_input.run_if(in_state(AppState::InGame)),
+                    keyboard::branch_input.run_if(in_state(AppState::InGame)),
                     keyboard::get_control_group.run_if(in_state(AppState::InGame)),
                     keyboard::set_control_group.run_if(in_state(AppState::InGame)),
                     selection,
                     ))
+            .add_systems(Update, (
+                    keyboard::clock_input.run_if(in_state(AppState::InGame)),
+                    keyboard::patrol,
+                    keyboard::cycle_formation,
+                    keyboard::toggle_history_mode,
+                    keyboard::toggle_reverse_fire,
+                    keyboard::toggle_paradox_rule,
+                    ))
             .add_systems(Update, next_state)
             .add_event::<Select>()
             .add_event::<Deselect>()
             .add_event::<Do>()
+            .add_event::<Queue>()
             .add_event::<Repeat>()
             .add_event::<Reverse>()
+            .add_event::<Export>()
+            .add_event::<Import>()
+            .add_event::<SelectBranch>()
+            .add_event::<CompareBranch>()
             .insert_resource(ControlGroups {
                 groups: HashMap::default()
             })
+        .insert_resource(Formation::Point)
+        .insert_resource(PendingOrder {
+            state: None,
+            swallow: false,
+        })
         .insert_resource(DoubleClick {
             timer: Timer::from_seconds(0.2, TimerMode::Once)
         })
@@ -1,12 +1,13 @@
-use bevy::{prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}};
+use bevy::{prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}, utils::HashMap};
 
 use crate::input::component::{self, AsVec2};
-use crate::unit::component::{Radius, Target};
-use super::{Deselect, Select, Do};
-use crate::unit::State::{Attack, Move};
+use crate::unit::component::{FormationSlot, Ghost, OrderQueue, Radius, Target};
+use super::{formation, Deselect, Select, Do, Formation, Queue, PendingOrder};
+use crate::unit::State::{self, Attack, AttackMove, Move};
 
 const BOX_COLOR: Color = Color::rgba(0.0, 1.0, 0.0, 0.25);
 const CLICK_ACCURACY: f32 = 2.0;
+const QUEUE: KeyCode = KeyCode::ShiftLeft;
 
 pub fn spawn_box(
     mut commands: Commands,
@@ -69,7 +70,7 @@ pub fn show_hide_box(
 pub fn double_click_timer(
     mouse_input: Res<ButtonInput<MouseButton>>,
     mut timer: ResMut<super::DoubleClick>,
-    time: Res<Time>,
+    time: Res<Time<Real>>,
     ) {
     if timer.timer.finished() {
         if mouse_input.just_released(MouseButton::Left) {
@@ -90,7 +91,14 @@ pub fn select_entities(
     cameras: Query<(&Camera, &GlobalTransform)>,
     mouse_input: Res<ButtonInput<MouseButton>>,
     timer: ResMut<super::DoubleClick>,
+    mut pending: ResMut<PendingOrder>,
 ) {
+    if pending.swallow {
+        if mouse_input.just_released(MouseButton::Left) {
+            pending.swallow = false;
+        }
+        return;
+    }
     if mouse_input.pressed(MouseButton::Left) && !mouse_input.just_pressed(MouseButton::Left) {
         let (camera, camera_transform) = cameras.single();
         if let Some(cursor_position) = windows.single().cursor_position() {
@@ -184,33 +192,92 @@ pub fn select_entities(
     }
 }
 
+/// Marks a unit as walking in formation towards `slot`, or clears the mark for any other order.
+fn set_formation_slot(commands: &mut Commands, entity: Entity, state: State, in_formation: bool, anchor: Vec2, slot: Vec2) {
+    if in_formation && (state == Move || state == AttackMove) {
+        commands.entity(entity).insert(FormationSlot { anchor, position: slot, pace: f32::MAX });
+    } else {
+        commands.entity(entity).remove::<FormationSlot>();
+    }
+}
+
 pub fn act(
+    mut commands: Commands,
     mut do_writer: EventWriter<Do>,
+    mut queue_writer: EventWriter<Queue>,
+    mut pending: ResMut<PendingOrder>,
     windows: Query<&Window>,
     cameras: Query<(&Camera, &GlobalTransform)>,
     mouse_input: Res<ButtonInput<MouseButton>>,
-    mut selection_query: Query<(Entity, &mut Target), With<component::Selected>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    formation: Res<Formation>,
+    mut selection_query: Query<(Entity, &Transform, &mut Target, &mut OrderQueue), (With<component::Selected>, Without<Ghost>)>,
     target_query: Query<(Entity, &Transform, &Radius), Without<component::Selected>>
     ) {
+    let queued = keyboard_input.pressed(QUEUE);
+    let units: Vec<(Entity, Vec2)> = selection_query.iter().map(|(entity, transform, _, _)| (entity, transform.translation.xy())).collect();
+    let slots_for = |point: Vec2| -> HashMap<Entity, Vec2> {
+        units.iter().map(|(entity, _)| *entity).zip(formation::slots(*formation, &units, point)).collect()
+    };
+    let in_formation = *formation != Formation::Point && units.len() > 1;
+    if let Some(state) = pending.state {
+        if mouse_input.just_pressed(MouseButton::Left) || mouse_input.just_pressed(MouseButton::Right) {
+            let (camera, camera_transform) = cameras.single();
+            if let Some(cursor_position) = windows.single().cursor_position() {
+                if let Some(position) = camera.viewport_to_world_2d(camera_transform, cursor_position) {
+                    let slots = slots_for(position.xy());
+                    for (entity, _, _, mut queue) in selection_query.iter_mut() {
+                        let slot = slots.get(&entity).copied().unwrap_or(position.xy());
+                        if queued {
+                            queue_writer.send(super::Queue(entity, state, slot));
+                        } else {
+                            queue.orders.clear();
+                            set_formation_slot(&mut commands, entity, state, in_formation, position.xy(), slot);
+                            do_writer.send(super::Do(entity, state, slot));
+                        }
+                    }
+                }
+            }
+            pending.state = None;
+            pending.swallow = mouse_input.just_pressed(MouseButton::Left);
+        }
+        return;
+    }
     if mouse_input.just_pressed(MouseButton::Right) {
         let (camera, camera_transform) = cameras.single();
         if let Some(cursor_position) = windows.single().cursor_position() {
             if let Some(position) = camera.viewport_to_world_2d(camera_transform, cursor_position) {
-                for (entity, mut target) in selection_query.iter_mut() {
+                let slots = slots_for(position.xy());
+                for (entity, _, mut target, mut queue) in selection_query.iter_mut() {
+                    let slot = slots.get(&entity).copied().unwrap_or(position.xy());
                     let mut target_found = false;
                     for (target_entity, transform, radius) in target_query.iter() {
                         let distance = transform.translation.xy().distance(position);
                         if distance <= radius.value {
                             target_found = true;
+                            if queued {
+                                continue;
+                            }
                             target.entity = Some(target_entity);
                             target.x = transform.translation.x;
                             target.y = transform.translation.y;
                         }
                     }
+                    if queued {
+                        if target_found {
+                            queue_writer.send(super::Queue(entity, Attack, position.xy()));
+                        } else {
+                            queue_writer.send(super::Queue(entity, Move, slot));
+                        }
+                        continue;
+                    }
+                    queue.orders.clear();
                     if target_found {
+                        set_formation_slot(&mut commands, entity, Attack, in_formation, position.xy(), slot);
                         do_writer.send(super::Do(entity, Attack, position.xy()));
                     } else {
-                        do_writer.send(super::Do(entity, Move, position.xy()));
+                        set_formation_slot(&mut commands, entity, Move, in_formation, position.xy(), slot);
+                        do_writer.send(super::Do(entity, Move, slot));
                     }
                 }
             }
@@ -0,0 +1,26 @@
+// Bevy systems take their queries and resources as arguments, which these lints flag by design.
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+use bevy::prelude::*;
+
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod bullet;
+pub mod camera;
+pub mod game;
+pub mod input;
+pub mod map;
+pub mod ui;
+pub mod unit;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, States)]
+pub enum AppState{
+    #[default]
+    Start,
+    RoundStart,
+    RoundEnd,
+    InGame,
+    Pause,
+    Win,
+    Loss,
+}
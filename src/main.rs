@@ -1,23 +1,6 @@
 use bevy::prelude::*;
 
-mod bullet;
-mod camera;
-mod game;
-mod input;
-mod ui;
-mod unit;
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, States)]
-pub enum AppState{
-    #[default]
-    Start,
-    RoundStart,
-    RoundEnd,
-    InGame,
-    Pause,
-    Win,
-    Loss,
-}
+use time_stabs::{bullet, camera, game, input, map, ui, unit, AppState};
 
 fn main() {
     let mut app = App::new();
@@ -28,7 +11,9 @@ fn main() {
          unit::UnitPlugin,
          bullet::BulletPlugin,
          camera::CameraPlugin,
-         game::GamePlugin
+         game::GamePlugin,
+         map::MapPlugin,
+         ui::UiPlugin,
         ))
         .init_state::<AppState>()
         .run();
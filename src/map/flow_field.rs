@@ -0,0 +1,72 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use bevy::prelude::*;
+
+use super::NavGrid;
+use super::pathfinding::{cuts_corner, nearest_free, NEIGHBOURS};
+
+/// Cost of walking from every cell of the grid to one goal, shared by all units sent there.
+pub struct FlowField {
+    pub destination: Vec2,
+    cost: Vec<u32>,
+}
+
+impl FlowField {
+    /// Integrates outwards from the goal cell. Cells that cannot reach it keep `u32::MAX`.
+    pub fn new(grid: &NavGrid, to: Vec2) -> Option<Self> {
+        let goal_cell = grid.cell(to)?;
+        let goal = nearest_free(grid, goal_cell)?;
+        let destination = if goal == goal_cell { to } else { grid.centre(goal) };
+
+        let mut cost = vec![u32::MAX; (grid.width * grid.height) as usize];
+        let mut open = BinaryHeap::new();
+        cost[grid.index(goal)] = 0;
+        open.push(Reverse((0, grid.index(goal))));
+        while let Some(Reverse((current_cost, index))) = open.pop() {
+            if current_cost > cost[index] {
+                continue;
+            }
+            let cell = IVec2::new(index as i32 % grid.width, index as i32 / grid.width);
+            for (offset, step) in NEIGHBOURS.iter() {
+                let next = cell + *offset;
+                if grid.is_blocked(next) || cuts_corner(grid, cell, *offset) {
+                    continue;
+                }
+                let next_index = grid.index(next);
+                let next_cost = current_cost + step;
+                if next_cost < cost[next_index] {
+                    cost[next_index] = next_cost;
+                    open.push(Reverse((next_cost, next_index)));
+                }
+            }
+        }
+        Some(FlowField { destination, cost })
+    }
+
+    /// Waypoints for a unit at `position`: the neighbouring cell closest to the goal, then the
+    /// destination itself. Units with a clear line to the destination walk straight at it.
+    pub fn waypoints(&self, grid: &NavGrid, position: Vec2) -> Vec<Vec2> {
+        if grid.line_of_sight(position, self.destination) {
+            return vec![self.destination];
+        }
+        let Some(cell) = grid.cell(position) else {
+            return vec![self.destination];
+        };
+        let mut best: Option<(u32, IVec2)> = None;
+        for (offset, _) in NEIGHBOURS.iter() {
+            let next = cell + *offset;
+            if grid.is_blocked(next) || cuts_corner(grid, cell, *offset) {
+                continue;
+            }
+            let next_cost = self.cost[grid.index(next)];
+            if best.is_none_or(|(best_cost, _)| next_cost < best_cost) {
+                best = Some((next_cost, next));
+            }
+        }
+        match best {
+            Some((next_cost, next)) if next_cost != u32::MAX => vec![grid.centre(next), self.destination],
+            _ => vec![self.destination],
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use bevy::{prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}, utils::HashMap};
+
+pub mod flow_field;
+pub mod pathfinding;
+pub mod spatial;
+
+const MAP_HALF_EXTENT: f32 = 2000.0;
+const CELL_SIZE: f32 = 25.0;
+/// Space kept between walls and the centre of a unit walking past them.
+const CLEARANCE: f32 = 20.0;
+const SPATIAL_CELL_SIZE: f32 = 100.0;
+const OBSTACLE_COLOR: Color = Color::rgb(0.3, 0.3, 0.35);
+const WALLS: [(Vec2, Vec2); 4] = [
+    (Vec2::new(-400.0, 150.0), Vec2::new(-370.0, 600.0)),
+    (Vec2::new(300.0, -400.0), Vec2::new(700.0, -370.0)),
+    (Vec2::new(400.0, 100.0), Vec2::new(430.0, 500.0)),
+    (Vec2::new(-700.0, -500.0), Vec2::new(-300.0, -470.0)),
+];
+
+/// Static walls units have to walk around.
+#[derive(Resource)]
+pub struct Obstacles {
+    pub rects: Vec<Rect>,
+}
+
+impl Obstacles {
+    /// Where a circle at `position` has to move to no longer overlap any wall.
+    pub fn push_out(&self, position: Vec2, radius: f32) -> Vec2 {
+        let mut position = position;
+        for rect in self.rects.iter() {
+            let closest = position.clamp(rect.min, rect.max);
+            let offset = position - closest;
+            let distance = offset.length();
+            if distance >= radius {
+                continue;
+            }
+            if distance > 0.0 {
+                position = closest + offset / distance * radius;
+            } else {
+                // The centre is inside the wall, leave through the nearest side.
+                let exits = [
+                    (position.x - rect.min.x, Vec2::new(rect.min.x - radius, position.y)),
+                    (rect.max.x - position.x, Vec2::new(rect.max.x + radius, position.y)),
+                    (position.y - rect.min.y, Vec2::new(position.x, rect.min.y - radius)),
+                    (rect.max.y - position.y, Vec2::new(position.x, rect.max.y + radius)),
+                ];
+                let mut best = exits[0];
+                for exit in exits.iter().skip(1) {
+                    if exit.0 < best.0 {
+                        best = *exit;
+                    }
+                }
+                position = best.1;
+            }
+        }
+        position
+    }
+}
+
+/// The map split into square cells, a cell being blocked when a unit standing in it would
+/// touch a wall.
+#[derive(Resource)]
+pub struct NavGrid {
+    pub origin: Vec2,
+    pub cell_size: f32,
+    pub width: i32,
+    pub height: i32,
+    pub blocked: Vec<bool>,
+}
+
+impl NavGrid {
+    pub fn new(obstacles: &Obstacles, half_extent: f32, cell_size: f32, clearance: f32) -> Self {
+        let origin = Vec2::splat(-half_extent);
+        let width = (half_extent * 2.0 / cell_size).ceil() as i32;
+        let height = width;
+        let mut blocked = vec![false; (width * height) as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let centre = origin + (Vec2::new(x as f32, y as f32) + 0.5) * cell_size;
+                blocked[(y * width + x) as usize] = obstacles.rects.iter()
+                    .any(|rect| centre.distance(centre.clamp(rect.min, rect.max)) < clearance);
+            }
+        }
+        NavGrid { origin, cell_size, width, height, blocked }
+    }
+
+    pub fn cell(&self, position: Vec2) -> Option<IVec2> {
+        let cell = ((position - self.origin) / self.cell_size).floor().as_ivec2();
+        if self.contains(cell) {
+            Some(cell)
+        } else {
+            None
+        }
+    }
+
+    pub fn centre(&self, cell: IVec2) -> Vec2 {
+        self.origin + (cell.as_vec2() + 0.5) * self.cell_size
+    }
+
+    pub fn contains(&self, cell: IVec2) -> bool {
+        cell.x >= 0 && cell.y >= 0 && cell.x < self.width && cell.y < self.height
+    }
+
+    pub fn index(&self, cell: IVec2) -> usize {
+        (cell.y * self.width + cell.x) as usize
+    }
+
+    pub fn is_blocked(&self, cell: IVec2) -> bool {
+        !self.contains(cell) || self.blocked[self.index(cell)]
+    }
+
+    /// Whether a straight walk from `from` to `to` stays clear of blocked cells.
+    pub fn line_of_sight(&self, from: Vec2, to: Vec2) -> bool {
+        let steps = (from.distance(to) / (self.cell_size * 0.5)).ceil().max(1.0) as i32;
+        (0..=steps).all(|step| {
+            let point = from.lerp(to, step as f32 / steps as f32);
+            match self.cell(point) {
+                Some(cell) => !self.is_blocked(cell),
+                None => true,
+            }
+        })
+    }
+}
+
+/// Flow fields of the group orders in progress, keyed by the exact target they lead to.
+#[derive(Resource)]
+pub struct FlowFields {
+    pub fields: HashMap<(u32, u32), flow_field::FlowField>,
+}
+
+impl FlowFields {
+    pub fn key(target: Vec2) -> (u32, u32) {
+        (target.x.to_bits(), target.y.to_bits())
+    }
+}
+
+pub struct MapPlugin;
+
+impl Plugin for MapPlugin {
+    fn build(&self, app: &mut App) {
+        let obstacles = Obstacles {
+            rects: WALLS.iter().map(|(min, max)| Rect::from_corners(*min, *max)).collect(),
+        };
+        let grid = NavGrid::new(&obstacles, MAP_HALF_EXTENT, CELL_SIZE, CLEARANCE);
+        app.insert_resource(obstacles)
+            .insert_resource(grid)
+            .insert_resource(FlowFields { fields: HashMap::new() })
+            .insert_resource(spatial::SpatialGrid::new(SPATIAL_CELL_SIZE))
+            .add_systems(Startup, spawn_obstacles);
+    }
+}
+
+pub fn spawn_obstacles(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    obstacles: Res<Obstacles>,
+    ) {
+    for rect in obstacles.rects.iter() {
+        let size = rect.size();
+        commands.spawn(MaterialMesh2dBundle {
+            mesh: Mesh2dHandle(meshes.add(Rectangle::new(size.x, size.y))),
+            material: materials.add(OBSTACLE_COLOR),
+            transform: Transform::from_translation(rect.center().extend(-rect.center().y)),
+            ..default()
+        });
+    }
+}
@@ -0,0 +1,138 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use bevy::prelude::*;
+
+use super::NavGrid;
+
+pub const STRAIGHT_COST: u32 = 10;
+pub const DIAGONAL_COST: u32 = 14;
+pub const NEIGHBOURS: [(IVec2, u32); 8] = [
+    (IVec2::new(1, 0), STRAIGHT_COST),
+    (IVec2::new(-1, 0), STRAIGHT_COST),
+    (IVec2::new(0, 1), STRAIGHT_COST),
+    (IVec2::new(0, -1), STRAIGHT_COST),
+    (IVec2::new(1, 1), DIAGONAL_COST),
+    (IVec2::new(-1, 1), DIAGONAL_COST),
+    (IVec2::new(1, -1), DIAGONAL_COST),
+    (IVec2::new(-1, -1), DIAGONAL_COST),
+];
+/// How far around a blocked goal to look for a free cell to walk to instead.
+const GOAL_SEARCH_RADIUS: i32 = 8;
+
+/// Octile distance, never overestimating the cost of the cheapest path.
+pub fn heuristic(from: IVec2, to: IVec2) -> u32 {
+    let delta = (to - from).abs();
+    let straight = delta.x.max(delta.y) - delta.x.min(delta.y);
+    let diagonal = delta.x.min(delta.y);
+    straight as u32 * STRAIGHT_COST + diagonal as u32 * DIAGONAL_COST
+}
+
+/// The nearest free cell to a blocked one, scanning rings outwards in a fixed order.
+pub fn nearest_free(grid: &NavGrid, cell: IVec2) -> Option<IVec2> {
+    if !grid.is_blocked(cell) {
+        return Some(cell);
+    }
+    for radius in 1..=GOAL_SEARCH_RADIUS {
+        let mut best: Option<(u32, IVec2)> = None;
+        for y in -radius..=radius {
+            for x in -radius..=radius {
+                if x.abs() != radius && y.abs() != radius {
+                    continue;
+                }
+                let candidate = cell + IVec2::new(x, y);
+                if grid.is_blocked(candidate) {
+                    continue;
+                }
+                let cost = heuristic(cell, candidate);
+                if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                    best = Some((cost, candidate));
+                }
+            }
+        }
+        if let Some((_, candidate)) = best {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Grid A* from `from` to `to`, returning the waypoints to walk through with `to` (or the
+/// closest reachable point to it) last. Ties are broken on cell index, so the same grid and
+/// endpoints always give the same path and replays walk the routes they recorded.
+pub fn find_path(grid: &NavGrid, from: Vec2, to: Vec2) -> Option<Vec<Vec2>> {
+    if grid.line_of_sight(from, to) {
+        return Some(vec![to]);
+    }
+    let (Some(start), Some(goal_cell)) = (grid.cell(from), grid.cell(to)) else {
+        return None;
+    };
+    let goal = nearest_free(grid, goal_cell)?;
+    let destination = if goal == goal_cell { to } else { grid.centre(goal) };
+
+    let size = (grid.width * grid.height) as usize;
+    let mut cost = vec![u32::MAX; size];
+    let mut came_from = vec![usize::MAX; size];
+    let mut open = BinaryHeap::new();
+    cost[grid.index(start)] = 0;
+    open.push(Reverse((heuristic(start, goal), grid.index(start))));
+
+    while let Some(Reverse((_, index))) = open.pop() {
+        let cell = IVec2::new(index as i32 % grid.width, index as i32 / grid.width);
+        if cell == goal {
+            let mut cells = vec![cell];
+            let mut current = index;
+            while came_from[current] != usize::MAX {
+                current = came_from[current];
+                cells.push(IVec2::new(current as i32 % grid.width, current as i32 / grid.width));
+            }
+            cells.reverse();
+            let mut points: Vec<Vec2> = cells.iter().skip(1).map(|cell| grid.centre(*cell)).collect();
+            points.pop();
+            points.push(destination);
+            return Some(smooth(grid, from, points));
+        }
+        for (offset, step) in NEIGHBOURS.iter() {
+            let next = cell + *offset;
+            if grid.is_blocked(next) {
+                continue;
+            }
+            if cuts_corner(grid, cell, *offset) {
+                continue;
+            }
+            let next_index = grid.index(next);
+            let next_cost = cost[index] + step;
+            if next_cost < cost[next_index] {
+                cost[next_index] = next_cost;
+                came_from[next_index] = index;
+                open.push(Reverse((next_cost + heuristic(next, goal), next_index)));
+            }
+        }
+    }
+    None
+}
+
+/// Whether a diagonal step squeezes past the corner of a wall.
+pub fn cuts_corner(grid: &NavGrid, cell: IVec2, offset: IVec2) -> bool {
+    offset.x != 0 && offset.y != 0
+        && (grid.is_blocked(cell + IVec2::new(offset.x, 0)) || grid.is_blocked(cell + IVec2::new(0, offset.y)))
+}
+
+/// Drops every waypoint that can be skipped by walking straight to a later one.
+fn smooth(grid: &NavGrid, from: Vec2, points: Vec<Vec2>) -> Vec<Vec2> {
+    let mut smoothed = Vec::new();
+    let mut anchor = from;
+    let mut index = 0;
+    while index < points.len() {
+        let mut furthest = index;
+        for (candidate, point) in points.iter().enumerate().skip(index + 1) {
+            if grid.line_of_sight(anchor, *point) {
+                furthest = candidate;
+            }
+        }
+        anchor = points[furthest];
+        smoothed.push(anchor);
+        index = furthest + 1;
+    }
+    smoothed
+}
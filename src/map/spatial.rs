@@ -0,0 +1,47 @@
+use bevy::{prelude::*, utils::HashMap};
+
+/// Units bucketed into square cells by position, rebuilt every frame so collision and target
+/// acquisition only look at the cells around them instead of every other unit.
+#[derive(Resource)]
+pub struct SpatialGrid {
+    pub cell_size: f32,
+    pub cells: HashMap<IVec2, Vec<(Entity, Vec2)>>,
+    /// Largest radius inserted since the last clear, the slack a circle query needs.
+    pub max_radius: f32,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        SpatialGrid { cell_size, cells: HashMap::new(), max_radius: 0.0 }
+    }
+
+    fn cell(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    /// Empties every cell, keeping their allocations for the next rebuild.
+    pub fn clear(&mut self) {
+        for entities in self.cells.values_mut() {
+            entities.clear();
+        }
+        self.max_radius = 0.0;
+    }
+
+    pub fn insert(&mut self, entity: Entity, position: Vec2, radius: f32) {
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_default().push((entity, position));
+        self.max_radius = self.max_radius.max(radius);
+    }
+
+    /// Everything in the cells touched by a circle of `distance` around `position`, row by row
+    /// so the order only depends on positions and insertion order. Callers still check the
+    /// exact distance.
+    pub fn nearby(&self, position: Vec2, distance: f32) -> impl Iterator<Item = (Entity, Vec2)> + '_ {
+        let min = self.cell(position - Vec2::splat(distance));
+        let max = self.cell(position + Vec2::splat(distance));
+        (min.y..=max.y)
+            .flat_map(move |y| (min.x..=max.x).map(move |x| IVec2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flat_map(|entities| entities.iter().copied())
+    }
+}
@@ -1,3 +1,7 @@
 use bevy::prelude::*;
 
-
+#[derive(Component)]
+pub struct EnergyUi {
+    pub refused: Option<f32>,
+    pub timer: Timer,
+}
@@ -1,11 +1,68 @@
 use bevy::prelude::*;
 
+use crate::unit::{ChronoEnergy, Refused};
+
 mod component;
 
+const PLAYER: usize = 0;
+const ENERGY_FONT_SIZE: f32 = 24.0;
+const ENERGY_COLOR: Color = Color::BLACK;
+const REFUSED_COLOR: Color = Color::RED;
+const REFUSED_DURATION: f32 = 1.5;
+
 pub struct UiPlugin;
 
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
-        app;
+        app.add_systems(Startup, spawn_energy_ui)
+            .add_systems(Update, energy_ui);
+    }
+}
+
+fn spawn_energy_ui(
+    mut commands: Commands,
+    ) {
+    commands.spawn((
+            TextBundle::from_section("", TextStyle {
+                font_size: ENERGY_FONT_SIZE,
+                color: ENERGY_COLOR,
+                ..default()
+            })
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.0),
+                left: Val::Px(10.0),
+                ..default()
+            }),
+            component::EnergyUi { refused: None, timer: Timer::from_seconds(REFUSED_DURATION, TimerMode::Once) },
+            ));
+}
+
+fn energy_ui(
+    mut refused_reader: EventReader<Refused>,
+    mut query: Query<(&mut Text, &mut component::EnergyUi)>,
+    energy: Res<ChronoEnergy>,
+    time: Res<Time<Real>>,
+    ) {
+    for (mut text, mut energy_ui) in query.iter_mut() {
+        for event in refused_reader.read() {
+            if event.0 == PLAYER {
+                energy_ui.refused = Some(event.1);
+                energy_ui.timer.reset();
+            }
+        }
+        energy_ui.timer.tick(time.delta());
+        if energy_ui.timer.finished() {
+            energy_ui.refused = None;
+        }
+        let available = energy.available(PLAYER);
+        let section = &mut text.sections[0];
+        if let Some(cost) = energy_ui.refused {
+            section.value = format!("Chrono energy: {:.0} / {:.0} - needs {:.0}", available, energy.max, cost);
+            section.style.color = REFUSED_COLOR;
+        } else {
+            section.value = format!("Chrono energy: {:.0} / {:.0}", available, energy.max);
+            section.style.color = ENERGY_COLOR;
+        }
     }
 }
@@ -1,13 +1,21 @@
 use std::f32::consts::PI;
 
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::input::Do;
+use crate::input::{component::Selected, Do, Queue};
 use super::State;
 use super::{component, component::AsVec2};
 use crate::bullet::Fire;
+use crate::map::spatial::SpatialGrid;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub const MUZZLE_DISTANCE: f32 = 50.0;
+const WAYPOINT_RADIUS: f32 = 6.0;
+const MOVE_WAYPOINT_COLOR: Color = Color::GREEN;
+const ATTACK_WAYPOINT_COLOR: Color = Color::RED;
+const ATTACK_MOVE_WAYPOINT_COLOR: Color = Color::ORANGE;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Action {
     None,
     Attack,
@@ -17,34 +25,95 @@ pub enum Action {
 }
 
 pub fn read_action(
+    mut commands: Commands,
     mut do_event: EventReader<Do>,
-    mut query: Query<(&mut component::Target, &mut component::CurrentState, &mut component::CurrentAction), With<component::Unit>>,
+    mut query: Query<(&mut component::Target, &mut component::CurrentState, &mut component::CurrentAction, &Transform), With<component::Unit>>,
     ) {
     for event in do_event.read() {
         match event.1 {
             State::Move => {
-                if let Ok((mut target, mut state, mut action)) = query.get_mut(event.0) {
+                if let Ok((mut target, mut state, mut action, _)) = query.get_mut(event.0) {
                     target.x = event.2.x;
                     target.y = event.2.y;
                     state.value = State::Move;
                     action.value = Action::None;
+                    commands.entity(event.0).remove::<component::Destination>();
+                    commands.entity(event.0).remove::<component::HoldPosition>();
+                    commands.entity(event.0).remove::<component::Patrol>();
+                }
+            },
+            State::AttackMove => {
+                if let Ok((mut target, mut state, mut action, _)) = query.get_mut(event.0) {
+                    target.x = event.2.x;
+                    target.y = event.2.y;
+                    target.entity = None;
+                    state.value = State::AttackMove;
+                    action.value = Action::None;
+                    commands.entity(event.0).insert(component::Destination { x: event.2.x, y: event.2.y });
+                    commands.entity(event.0).remove::<component::HoldPosition>();
+                    commands.entity(event.0).remove::<component::Patrol>();
                 }
             },
             State::Attack => {
-                if let Ok((mut target, mut state, mut action)) = query.get_mut(event.0) {
+                if let Ok((mut target, mut state, mut action, _)) = query.get_mut(event.0) {
                     target.x = event.2.x;
                     target.y = event.2.y;
                     state.value = State::Attack;
                     action.value = Action::None;
+                    // An attack ordered by the player replaces whatever the unit was doing.
+                    commands.entity(event.0).remove::<component::Destination>();
+                    commands.entity(event.0).remove::<component::HoldPosition>();
+                    commands.entity(event.0).remove::<component::Patrol>();
                 }
             },
             State::Idle => {
-                if let Ok((mut target, mut state, mut action)) = query.get_mut(event.0) {
+                if let Ok((mut target, mut state, mut action, _)) = query.get_mut(event.0) {
                     target.x = event.2.x;
                     target.y = event.2.y;
                     target.entity = None;
                     state.value = State::Idle;
                     action.value = Action::None;
+                    commands.entity(event.0).remove::<component::Destination>();
+                    commands.entity(event.0).remove::<component::HoldPosition>();
+                    commands.entity(event.0).remove::<component::Patrol>();
+                }
+            },
+            // Stop and hold stay where the unit is when the order arrives rather than where it
+            // was recorded, so replayed orders never pull a unit across the map.
+            State::Stop => {
+                if let Ok((mut target, mut state, mut action, transform)) = query.get_mut(event.0) {
+                    target.x = transform.translation.x;
+                    target.y = transform.translation.y;
+                    target.entity = None;
+                    state.value = State::Idle;
+                    action.value = Action::None;
+                    commands.entity(event.0).remove::<component::Destination>();
+                    commands.entity(event.0).remove::<component::HoldPosition>();
+                    commands.entity(event.0).remove::<component::Patrol>();
+                }
+            },
+            State::Halt => {
+                if let Ok((mut target, mut state, mut action, transform)) = query.get_mut(event.0) {
+                    target.x = transform.translation.x;
+                    target.y = transform.translation.y;
+                    target.entity = None;
+                    state.value = State::Halt;
+                    action.value = Action::None;
+                    commands.entity(event.0).remove::<component::Destination>();
+                    commands.entity(event.0).insert(component::HoldPosition);
+                    commands.entity(event.0).remove::<component::Patrol>();
+                }
+            },
+            State::Patrol => {
+                if let Ok((mut target, mut state, mut action, transform)) = query.get_mut(event.0) {
+                    target.x = event.2.x;
+                    target.y = event.2.y;
+                    target.entity = None;
+                    state.value = State::Patrol;
+                    action.value = Action::None;
+                    commands.entity(event.0).remove::<component::Destination>();
+                    commands.entity(event.0).remove::<component::HoldPosition>();
+                    commands.entity(event.0).insert(component::Patrol { points: vec![transform.translation.xy(), event.2], next: 1 });
                 }
             },
             _ => {}
@@ -52,6 +121,101 @@ pub fn read_action(
     }
 }
 
+pub fn read_queue(
+    mut queue_reader: EventReader<Queue>,
+    mut query: Query<(&component::Unit, &mut component::OrderQueue, Option<&mut component::Patrol>)>,
+    target_query: Query<(Entity, &Transform, &component::Radius, &component::Unit)>,
+    ) {
+    for event in queue_reader.read() {
+        let Ok((unit, mut queue, opt_patrol)) = query.get_mut(event.0) else {
+            continue;
+        };
+        // A patrol point queued onto a running patrol extends its route.
+        if let Some(mut patrol) = opt_patrol {
+            if event.1 == State::Patrol {
+                patrol.points.push(event.2);
+                continue;
+            }
+        }
+        let mut order = component::QueuedOrder { state: event.1, position: event.2, entity: None };
+        if event.1 == State::Attack {
+            for (target_entity, transform, radius, target_unit) in target_query.iter() {
+                if target_unit.owner != unit.owner && transform.translation.xy().distance(event.2) <= radius.value {
+                    order.entity = Some(target_entity);
+                }
+            }
+        }
+        queue.orders.push_back(order);
+    }
+}
+
+/// Hands the next queued order to an idle unit as a regular `Do`, so it is recorded like any other.
+pub fn advance_queue(
+    mut do_writer: EventWriter<Do>,
+    mut query: Query<(Entity, &mut component::OrderQueue, &mut component::Target, &component::CurrentState, Has<component::Ghost>, Has<component::Echo>)>,
+    transform_query: Query<&Transform>,
+    ) {
+    for (entity, mut queue, mut target, state, ghost, echo) in query.iter_mut() {
+        // Ghosts and echoes follow their recording, not the queue they were given.
+        if ghost || echo {
+            queue.orders.clear();
+            continue;
+        }
+        if state.value != State::Idle {
+            continue;
+        }
+        while let Some(order) = queue.orders.pop_front() {
+            if let Some(target_entity) = order.entity {
+                let Ok(transform) = transform_query.get(target_entity) else {
+                    continue;
+                };
+                target.entity = Some(target_entity);
+                do_writer.send(Do(entity, order.state, transform.translation.xy()));
+            } else {
+                do_writer.send(Do(entity, order.state, order.position));
+            }
+            break;
+        }
+    }
+}
+
+pub fn draw_waypoints(
+    mut gizmos: Gizmos,
+    query: Query<(&Transform, &component::Target, &component::CurrentState, &component::OrderQueue), With<Selected>>,
+    transform_query: Query<&Transform>,
+    ) {
+    for (transform, target, state, queue) in query.iter() {
+        if queue.orders.is_empty() {
+            continue;
+        }
+        let mut from = transform.translation.xy();
+        if state.value == State::Move || state.value == State::AttackMove || state.value == State::Attack || state.value == State::Patrol {
+            gizmos.line_2d(from, target.as_vec2(), waypoint_color(state.value));
+            from = target.as_vec2();
+        }
+        for order in queue.orders.iter() {
+            let mut to = order.position;
+            if let Some(target_entity) = order.entity {
+                if let Ok(target_transform) = transform_query.get(target_entity) {
+                    to = target_transform.translation.xy();
+                }
+            }
+            let color = waypoint_color(order.state);
+            gizmos.line_2d(from, to, color);
+            gizmos.circle_2d(to, WAYPOINT_RADIUS, color);
+            from = to;
+        }
+    }
+}
+
+fn waypoint_color(state: State) -> Color {
+    match state {
+        State::Attack => ATTACK_WAYPOINT_COLOR,
+        State::AttackMove | State::Patrol => ATTACK_MOVE_WAYPOINT_COLOR,
+        _ => MOVE_WAYPOINT_COLOR,
+    }
+}
+
 pub fn update_target_position(
     mut target_query: Query<&mut component::Target, With<component::Unit>>,
     transform_query: Query<&Transform>,
@@ -68,56 +232,97 @@ pub fn update_target_position(
 
 pub fn attack(
     mut fire_writer: EventWriter<Fire>,
-    mut query: Query<(&mut component::CurrentAction, &mut component::CurrentState, &mut component::Attack, &Transform, &component::Facing, &component::Target, &component::Unit)>,
+    mut query: Query<(Entity, &mut component::CurrentAction, &mut component::CurrentState, &mut component::Attack, &Transform, &component::Facing, &mut component::Target, &component::Unit, Option<&component::Destination>, Has<component::HoldPosition>, Has<component::Patrol>), Without<component::Ghost>>,
     time: Res<Time>,
     ) {
-    for (mut action, mut state, mut attack, transform, facing, target, unit) in query.iter_mut() {
+    // Ghosts fire from their recording. Echoes replay orders, not shots, so they fire here.
+    for (entity, mut action, mut state, mut attack, transform, facing, mut target, unit, opt_destination, holding, patrolling) in query.iter_mut() {
         if action.value == Action::Attack {
             let forward = Vec2::new(facing.value.cos(), facing.value.sin()).normalize();
             let to_target = (target.as_vec2() - transform.translation.xy()).normalize();
             let forward_dot_target = forward.dot(to_target);
             if (forward_dot_target - 1.0).abs() < f32::EPSILON {
-                fire_writer.send(Fire(unit.owner, transform.translation.xy() + (to_target * 50.0), facing.value - (PI / 2.0)));
+                fire_writer.send(Fire(entity, unit.owner, transform.translation.xy() + (to_target * MUZZLE_DISTANCE), facing.value - (PI / 2.0)));
                 action.value = Action::None;
                 attack.timer.reset();
             }
         }
         if state.value == State::Attack {
+            // A unit holding position lets targets go once they leave its range.
+            if holding && transform.translation.xy().distance(target.as_vec2()) > attack.range {
+                target.entity = None;
+            }
             if let None = target.entity {
                 action.value = Action::None;
                 state.value = State::Idle;
+                // The target is gone, an interrupted attack-move carries on.
+                if let Some(destination) = opt_destination {
+                    target.x = destination.x;
+                    target.y = destination.y;
+                    state.value = State::AttackMove;
+                }
+                if holding {
+                    target.x = transform.translation.x;
+                    target.y = transform.translation.y;
+                    state.value = State::Halt;
+                }
+                if patrolling {
+                    state.value = State::Patrol;
+                }
             }
             attack.timer.tick(time.delta());
             if attack.timer.finished() {
                 action.value = Action::Attack;
             }
         }
-        if state.value == State::Idle {
+        if state.value == State::Idle || state.value == State::Halt {
             attack.timer.reset();
         }
     }
 }
 
+/// Idle, holding, patrolling and attack-moving units take on the nearest enemy in range.
+/// The unit is switched to attacking directly instead of through a `Do`, so the order it
+/// was following is kept to resume afterwards and never ends up in a command log.
 pub fn engage(
-    mut do_writer: EventWriter<Do>,
-    mut query: Query<(Entity, &Transform, &mut component::CurrentState, &component::Unit, &mut component::Target, &component::Attack)>,
+    grid: Res<SpatialGrid>,
+    mut query: Query<(Entity, &Transform, &mut component::CurrentState, &mut component::CurrentAction, &component::Unit, &mut component::Target, &component::Attack, Has<component::Ghost>), Without<component::Dead>>,
     ) {
-    let mut combinations = query.iter_combinations_mut();
-    while let Some([mut unit1, mut unit2]) = combinations.fetch_next() {
-        if unit1.3.owner == unit2.3.owner {
+    let mut engagements = Vec::new();
+    for (entity, transform, state, _, unit, _, attack, ghost) in query.iter() {
+        // Ghosts only do what their recording did, but can still be shot at.
+        if ghost {
             continue;
         }
-        if unit1.2.value == State::AttackMove || unit1.2.value == State::Idle {
-            if unit1.1.translation.xy().distance(unit2.1.translation.xy()) <= unit1.5.range {
-                unit1.4.entity = Some(unit2.0);           
-                do_writer.send(Do(unit1.0, State::Attack, unit2.1.translation.xy()));
-            }
+        if state.value != State::AttackMove && state.value != State::Idle && state.value != State::Halt && state.value != State::Patrol {
+            continue;
         }
-        if unit2.2.value == State::AttackMove || unit2.2.value == State::Idle {
-            if unit2.1.translation.xy().distance(unit1.1.translation.xy()) <= unit2.5.range {
-                unit2.4.entity = Some(unit1.0);
-                do_writer.send(Do(unit2.0, State::Attack, unit1.1.translation.xy()));
+        let position = transform.translation.xy();
+        let mut nearest: Option<(f32, Entity, Vec2)> = None;
+        for (other, _) in grid.nearby(position, attack.range) {
+            let Ok((_, other_transform, _, _, other_unit, _, _, _)) = query.get(other) else {
+                continue;
+            };
+            if other_unit.owner == unit.owner {
+                continue;
             }
+            let other_position = other_transform.translation.xy();
+            let distance = position.distance(other_position);
+            if distance <= attack.range && nearest.is_none_or(|(best, _, _)| distance < best) {
+                nearest = Some((distance, other, other_position));
+            }
+        }
+        if let Some((_, other, other_position)) = nearest {
+            engagements.push((entity, other, other_position));
+        }
+    }
+    for (entity, other, other_position) in engagements {
+        if let Ok((_, _, mut state, mut action, _, mut target, _, _)) = query.get_mut(entity) {
+            target.entity = Some(other);
+            target.x = other_position.x;
+            target.y = other_position.y;
+            state.value = State::Attack;
+            action.value = Action::None;
         }
     }
 }
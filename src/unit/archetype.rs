@@ -0,0 +1,198 @@
+use std::{collections::VecDeque, fmt, io};
+use bevy::{asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext, LoadedFolder}, ecs::system::SystemParam, prelude::*, utils::{BoxedFuture, HashMap}};
+use serde::Deserialize;
+use super::{component, store::SnapshotStore, State, action::Action, health::spawn_unit_ui};
+
+pub const ARCHETYPE_DIRECTORY: &str = "units";
+
+/// A unit type, loaded from a `.unit.ron` file in `assets/units`.
+#[derive(Asset, TypePath, Deserialize)]
+pub struct Archetype {
+    pub name: String,
+    pub texture: String,
+    pub tile_size: Vec2,
+    pub columns: usize,
+    pub rows: usize,
+    pub radius: f32,
+    pub move_speed: f32,
+    pub turn_rate: f32,
+    pub attack_range: f32,
+    pub attack_cooldown: f32,
+    pub health: i32,
+    pub animation: Animation,
+}
+
+/// Atlas frames of an archetype. Each facing direction has its own row of `row_stride` frames
+/// for moving, and a pair of aiming and firing frames starting at `attack_first`.
+#[derive(Deserialize)]
+pub struct Animation {
+    pub frame_time: f32,
+    pub row_stride: usize,
+    pub move_first: usize,
+    pub move_last: usize,
+    pub attack_first: usize,
+    pub attack_stride: usize,
+    pub death_first: usize,
+    pub death_last: usize,
+}
+
+#[derive(Debug)]
+pub enum ArchetypeError {
+    Io(io::Error),
+    Format(ron::error::SpannedError),
+}
+
+impl fmt::Display for ArchetypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchetypeError::Io(error) => write!(f, "could not read archetype: {}", error),
+            ArchetypeError::Format(error) => write!(f, "archetype is malformed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ArchetypeError {}
+
+impl From<io::Error> for ArchetypeError {
+    fn from(error: io::Error) -> Self {
+        ArchetypeError::Io(error)
+    }
+}
+
+#[derive(Default)]
+pub struct ArchetypeLoader;
+
+impl AssetLoader for ArchetypeLoader {
+    type Asset = Archetype;
+    type Settings = ();
+    type Error = ArchetypeError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+        ) -> BoxedFuture<'a, Result<Archetype, ArchetypeError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            ron::de::from_bytes(&bytes).map_err(ArchetypeError::Format)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["unit.ron"]
+    }
+}
+
+/// Every loaded archetype by name. The folder handle keeps them loaded.
+#[derive(Resource)]
+pub struct Archetypes {
+    pub folder: Handle<LoadedFolder>,
+    pub by_name: HashMap<String, Handle<Archetype>>,
+}
+
+pub fn load_archetypes(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    ) {
+    commands.insert_resource(Archetypes {
+        folder: asset_server.load_folder(ARCHETYPE_DIRECTORY),
+        by_name: HashMap::new(),
+    });
+}
+
+pub fn index_archetypes(
+    mut folder_events: EventReader<AssetEvent<LoadedFolder>>,
+    mut asset_events: EventReader<AssetEvent<Archetype>>,
+    mut archetypes: ResMut<Archetypes>,
+    archetype_assets: Res<Assets<Archetype>>,
+    asset_server: Res<AssetServer>,
+    ) {
+    for event in folder_events.read() {
+        if event.is_loaded_with_dependencies(&archetypes.folder) {
+            info!("Loaded '{}' with {} unit archetypes", ARCHETYPE_DIRECTORY, archetype_assets.len());
+        }
+    }
+    for event in asset_events.read() {
+        match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                if let (Some(archetype), Some(handle)) = (archetype_assets.get(*id), asset_server.get_id_handle(*id)) {
+                    info!("Loaded unit archetype '{}'", archetype.name);
+                    archetypes.by_name.insert(archetype.name.clone(), handle);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The one place units are built. Spawns a unit of the named archetype with its selection
+/// circle and health bar; callers insert whatever sets it apart (owner markers, playback, ...).
+#[derive(SystemParam)]
+pub struct UnitSpawner<'w, 's> {
+    pub commands: Commands<'w, 's>,
+    asset_server: Res<'w, AssetServer>,
+    texture_atlas_layouts: ResMut<'w, Assets<TextureAtlasLayout>>,
+    meshes: ResMut<'w, Assets<Mesh>>,
+    materials: ResMut<'w, Assets<ColorMaterial>>,
+    archetypes: Res<'w, Archetypes>,
+    archetype_assets: Res<'w, Assets<Archetype>>,
+}
+
+impl<'w, 's> UnitSpawner<'w, 's> {
+    /// Whether the named archetype has finished loading and can be spawned.
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.archetypes.by_name.get(name).is_some_and(|handle| self.archetype_assets.contains(handle))
+    }
+
+    pub fn spawn(&mut self, name: &str, owner: usize, position: Vec3, facing: f32, color: Color) -> Option<Entity> {
+        let Some(handle) = self.archetypes.by_name.get(name) else {
+            error!("Unit archetype '{}' is not loaded", name);
+            return None;
+        };
+        let archetype = self.archetype_assets.get(handle)?;
+        let texture = self.asset_server.load::<Image>(archetype.texture.clone());
+        let layout = TextureAtlasLayout::from_grid(archetype.tile_size, archetype.columns, archetype.rows, None, None);
+        let texture_atlas_layout = self.texture_atlas_layouts.add(layout);
+        let parent = self.commands.spawn(SpriteSheetBundle {
+            sprite: Sprite {
+                color,
+                ..default()
+            },
+            texture,
+            atlas: TextureAtlas {
+                layout: texture_atlas_layout,
+                index: archetype.animation.move_first,
+            },
+            transform: Transform::from_translation(position),
+            ..default()
+        }).id();
+
+        self.commands.entity(parent).insert((
+                component::Unit { owner },
+                component::Kind { name: archetype.name.clone(), handle: handle.clone() },
+                component::Radius { value: archetype.radius },
+                component::Velocity { x: 0.0, y: 0.0 },
+                component::MoveSpeed { value: archetype.move_speed },
+                component::Facing { value: facing },
+                component::TurnRate { value: archetype.turn_rate },
+                component::Target { entity: None, x: position.x, y: position.y },
+                component::CurrentAction { value: Action::None },
+                component::Attack { range: archetype.attack_range, timer: Timer::from_seconds(archetype.attack_cooldown, TimerMode::Once) },
+                component::CurrentState { value: State::Idle },
+                component::History { snapshots: SnapshotStore::default() },
+                component::Health { current: archetype.health, max: archetype.health },
+                component::AnimationIndices { current: archetype.animation.move_first, first: archetype.animation.move_first, last: archetype.animation.move_last },
+                component::AnimationTimer { timer: Timer::from_seconds(archetype.animation.frame_time, TimerMode::Repeating) },
+                ))
+            .insert((
+                component::CommandLog { origin: None, commands: VecDeque::new(), checkpoints: VecDeque::new(), death: None, diverged: false },
+                component::OrderQueue { orders: VecDeque::new() },
+                component::Path { goal: None, waypoints: VecDeque::new(), flow: None, arrival: 0.0 },
+                ));
+
+        spawn_unit_ui(&mut self.commands, parent, &self.asset_server, &mut self.meshes, &mut self.materials);
+        Some(parent)
+    }
+}
@@ -1,22 +1,150 @@
 use bevy::prelude::*;
 
+use crate::map::{spatial::SpatialGrid, Obstacles};
 use super::component;
 
+/// Rebuilt once units have moved, been pushed apart and new ghosts have spawned, right
+/// before engagement and bullet hits read it. Steering reads the previous frame's grid.
+pub fn index_units(
+    mut grid: ResMut<SpatialGrid>,
+    query: Query<(Entity, &Transform, &component::Radius), (With<component::Unit>, Without<component::Dead>)>,
+    ) {
+    grid.clear();
+    for (entity, transform, radius) in query.iter() {
+        grid.insert(entity, transform.translation.xy(), radius.value);
+    }
+}
+
+pub fn obstacles(
+    mut query: Query<(&mut Transform, &component::Radius), (With<component::Unit>, Without<component::Ghost>, Without<component::Dead>)>,
+    obstacles: Res<Obstacles>,
+    ) {
+    for (mut transform, radius) in query.iter_mut() {
+        let position = obstacles.push_out(transform.translation.xy(), radius.value);
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
+
+/// Pushes overlapping units apart until they just touch, the smaller one giving way more.
+/// Separation steering keeps them from getting this close in the first place; this only
+/// resolves what is left, so units no longer bounce off each other. Units have just moved,
+/// so the grid is first rebuilt from where they are now. Repeating ghosts get pushed too and
+/// drift off their recording.
 pub fn collision(
-    mut query: Query<(&mut Transform, &mut component::Velocity, &component::Radius), (With<component::Unit>, Without<component::Ghost>, Without<component::Dead>)>,
+    mut grid: ResMut<SpatialGrid>,
+    mut query: Query<(Entity, &mut Transform, &component::Radius, Has<component::HoldPosition>), (With<component::Unit>, Or<(Without<component::Ghost>, With<component::Drift>)>, Without<component::Dead>)>,
     ) {
-    let mut combinations = query.iter_combinations_mut();
-    while let Some([mut unit1, mut unit2]) = combinations.fetch_next() {
-        let distance = unit1.0.translation.distance(unit2.0.translation).max(1.0);
+    grid.clear();
+    for (entity, transform, radius, _) in query.iter() {
+        grid.insert(entity, transform.translation.xy(), radius.value);
+    }
+    let mut pairs = Vec::new();
+    for (entity, transform, radius, _) in query.iter() {
+        for (other, _) in grid.nearby(transform.translation.xy(), radius.value + grid.max_radius) {
+            // Every pair once, and only those overlapping before anything is pushed.
+            if other <= entity {
+                continue;
+            }
+            let Ok((_, other_transform, other_radius, _)) = query.get(other) else {
+                continue;
+            };
+            if transform.translation.xy().distance(other_transform.translation.xy()) < radius.value + other_radius.value {
+                pairs.push([entity, other]);
+            }
+        }
+    }
+    // Resolved in entity order, so the result does not depend on how the grid buckets them.
+    pairs.sort();
+    for pair in pairs {
+        let Ok([mut unit1, mut unit2]) = query.get_many_mut(pair) else {
+            continue;
+        };
+        let offset = unit1.1.translation.xy() - unit2.1.translation.xy();
+        let distance = offset.length();
         let combined_radius = unit1.2.value + unit2.2.value;
         if distance < combined_radius {
-            let normal = (unit1.0.translation - unit2.0.translation).max(Vec3::ONE).normalize();
-            let unit1_percent = unit1.2.value / combined_radius * 100.0;
-            let unit2_percent = unit2.2.value / combined_radius * 100.0;
-            unit1.1.x += normal.x * unit1_percent;
-            unit1.1.y += normal.y * unit1_percent;
-            unit2.1.x -= normal.x * unit2_percent;
-            unit2.1.y -= normal.y * unit2_percent;
+            // Units on the same spot split along a fixed axis.
+            let normal = offset.try_normalize().unwrap_or(Vec2::X);
+            let overlap = combined_radius - distance;
+            let mut unit1_share = unit2.2.value / combined_radius;
+            let mut unit2_share = unit1.2.value / combined_radius;
+            // A unit holding position is not shoved, whoever walks into it gives way alone.
+            if unit1.3 && !unit2.3 {
+                unit1_share = 0.0;
+                unit2_share = 1.0;
+            } else if unit2.3 && !unit1.3 {
+                unit1_share = 1.0;
+                unit2_share = 0.0;
+            }
+            unit1.1.translation.x += normal.x * overlap * unit1_share;
+            unit1.1.translation.y += normal.y * overlap * unit1_share;
+            unit2.1.translation.x -= normal.x * overlap * unit2_share;
+            unit2.1.translation.y -= normal.y * overlap * unit2_share;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use crate::bullet::{self, BulletLog};
+    use crate::game::TimeCursor;
+    use crate::unit::{action::{self, Action}, State, UNIT_HEALTH};
+    use super::*;
+
+    const EXTENT: f32 = 1500.0;
+
+    /// Runs unit collision, target acquisition and bullet hits once over the same crowd and
+    /// bullets, returning where every unit ended up, what it targets and its health.
+    fn run(cell_size: f32, seed: u64) -> Vec<(Vec2, Option<Entity>, i32)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut app = App::new();
+        app.insert_resource(SpatialGrid::new(cell_size))
+            .insert_resource(TimeCursor { current: 0.0, step: 0.0, scale: 1.0, paused: false })
+            .insert_resource(BulletLog { shots: Vec::new() })
+            .add_systems(Update, (
+                    collision,
+                    index_units,
+                    action::engage,
+                    bullet::collision::collision,
+                    ).chain());
+        for n in 0..400 {
+            app.world.spawn((
+                    component::Unit { owner: n % 2 },
+                    Transform::from_xyz(rng.gen_range(0.0..EXTENT), rng.gen_range(0.0..EXTENT), 0.0),
+                    component::Radius { value: 20.0 },
+                    component::CurrentState { value: State::Idle },
+                    component::CurrentAction { value: Action::None },
+                    component::Target { entity: None, x: 0.0, y: 0.0 },
+                    component::Attack { range: 300.0, timer: Timer::from_seconds(1.0, TimerMode::Repeating) },
+                    component::Health { current: UNIT_HEALTH, max: UNIT_HEALTH },
+                    ));
+        }
+        for _ in 0..200 {
+            app.world.spawn((
+                    bullet::component::Bullet { owner: 0, shot: None },
+                    bullet::component::Damage { value: 10 },
+                    component::Radius { value: 5.0 },
+                    Transform::from_xyz(rng.gen_range(0.0..EXTENT), rng.gen_range(0.0..EXTENT), 0.0),
+                    ));
+        }
+        app.update();
+        let mut query = app.world.query::<(Entity, &Transform, &component::Target, &component::Health)>();
+        let mut rows: Vec<_> = query.iter(&app.world).collect();
+        rows.sort_by_key(|(entity, _, _, _)| *entity);
+        rows.into_iter().map(|(_, transform, target, health)| (transform.translation.xy(), target.entity, health.current)).collect()
+    }
+
+    #[test]
+    fn grid_matches_every_pair_check() {
+        for seed in 0..3 {
+            // A single infinite cell hands every unit to every check.
+            let naive = run(f32::INFINITY, seed);
+            let gridded = run(100.0, seed);
+            assert!(gridded.iter().any(|(_, target, _)| target.is_some()));
+            assert!(gridded.iter().any(|(_, _, health)| *health < UNIT_HEALTH));
+            assert_eq!(naive, gridded);
         }
     }
 }
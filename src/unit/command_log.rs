@@ -0,0 +1,222 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use crate::game::{Round, TimeCursor};
+use crate::input::{component::{Selectable, Selected}, Do, Queue, Repeat};
+use super::{component, archetype::UnitSpawner, GhostGenerations, Paradox, ParadoxKind, State, DEFAULT_COLOR, ENEMY_COLOR, GHOST_COLOR, PARADOX_DISTANCE, action::Action, lineage::{descend, spawn_lineage_ui}};
+
+const CHECKPOINT_INTERVAL: f32 = 0.25;
+
+#[derive(Clone)]
+pub struct Origin {
+    pub timestamp: f32,
+    pub position: Vec3,
+    pub facing: f32,
+}
+
+#[derive(Clone)]
+pub struct LoggedCommand {
+    pub timestamp: f32,
+    pub state: State,
+    pub position: Vec2,
+    pub from: Vec2,
+    pub queued: bool,
+    /// The unit an attack was ordered on, the position alone does not say who to shoot.
+    pub target: Option<Entity>,
+    /// The formation a move was ordered in, so the echo keeps to its slot and pace.
+    pub formation: Option<component::FormationSlot>,
+}
+
+pub fn track_commands(
+    mut do_reader: EventReader<Do>,
+    mut queue_reader: EventReader<Queue>,
+    mut query: Query<(&mut component::CommandLog, &Transform, &component::Facing, &component::CurrentState, &component::Target, Has<component::Patrol>, Option<&component::FormationSlot>), (With<component::Unit>, Without<component::Repeat>, Without<component::Reverse>)>,
+    cursor: Res<TimeCursor>,
+    ) {
+    // Only orders given by the player arrive here, engagements switch units to attacking
+    // without a `Do` and happen again by themselves on replay.
+    for (mut log, transform, facing, state, _, _, _) in query.iter_mut() {
+        if let None = log.origin {
+            log.origin = Some(Origin {
+                timestamp: cursor.current,
+                position: transform.translation,
+                facing: facing.value,
+            });
+        }
+        if state.value == State::Dead && log.death.is_none() {
+            log.death = Some(cursor.current);
+        }
+        if log.death.is_none() && log.checkpoints.back().is_none_or(|(timestamp, _)| cursor.current - timestamp >= CHECKPOINT_INTERVAL) {
+            log.checkpoints.push_back((cursor.current, transform.translation.xy()));
+        }
+    }
+    for event in do_reader.read() {
+        if let Ok((mut log, transform, _, _, target, _, opt_slot)) = query.get_mut(event.0) {
+            let mut attacked = None;
+            if event.1 == State::Attack {
+                attacked = target.entity;
+            }
+            // The slot is placed along with the order, before this runs.
+            let mut formation = None;
+            if event.1 == State::Move || event.1 == State::AttackMove {
+                formation = opt_slot.filter(|slot| slot.position == event.2).cloned();
+            }
+            log.commands.push_back(LoggedCommand {
+                timestamp: cursor.current,
+                state: event.1,
+                position: event.2,
+                from: transform.translation.xy(),
+                queued: false,
+                target: attacked,
+                formation,
+            });
+        }
+    }
+    // Points added to a running patrol never come back as a `Do`, so they are logged as queued.
+    for event in queue_reader.read() {
+        if event.1 != State::Patrol {
+            continue;
+        }
+        if let Ok((mut log, transform, _, _, _, true, _)) = query.get_mut(event.0) {
+            log.commands.push_back(LoggedCommand {
+                timestamp: cursor.current,
+                state: event.1,
+                position: event.2,
+                from: transform.translation.xy(),
+                queued: true,
+                target: None,
+                formation: None,
+            });
+        }
+    }
+}
+
+pub fn repeat_commands(
+    mut commands: Commands,
+    mut do_writer: EventWriter<Do>,
+    mut queue_writer: EventWriter<Queue>,
+    mut paradox_writer: EventWriter<Paradox>,
+    mut query: Query<(Entity, &mut Sprite, &Transform, &mut component::Target, &mut component::CommandLog, &mut component::Repeat, Option<&component::Enemy>), With<component::Echo>>,
+    time: Res<Time>,
+    ) {
+    for (entity, mut sprite, transform, mut target, mut log, mut repeat, opt_enemy) in query.iter_mut() {
+        let Some(origin) = log.origin.clone() else {
+            continue;
+        };
+        if !repeat.paused {
+            repeat.elapsed += time.delta_seconds() * repeat.rate;
+        }
+        // The echo has to be roughly where the recorded unit was, not only when it gives an order.
+        while log.checkpoints.front().is_some_and(|(timestamp, _)| timestamp - origin.timestamp <= repeat.elapsed) {
+            if let Some((_, position)) = log.checkpoints.pop_front() {
+                let divergence = transform.translation.xy().distance(position);
+                diverge(&mut paradox_writer, entity, &mut log, divergence);
+            }
+        }
+        while log.commands.front().is_some_and(|command| command.timestamp - origin.timestamp <= repeat.elapsed) {
+            if let Some(command) = log.commands.pop_front() {
+                // The recorded unit gave this order from `from`; an echo far away from it
+                // can no longer be following the same timeline.
+                let divergence = transform.translation.xy().distance(command.from);
+                diverge(&mut paradox_writer, entity, &mut log, divergence);
+                if command.queued {
+                    queue_writer.send(Queue(entity, command.state, command.position));
+                } else {
+                    if let Some(attacked) = command.target {
+                        target.entity = Some(attacked);
+                    }
+                    if let Some(slot) = command.formation {
+                        commands.entity(entity).insert(slot);
+                    } else {
+                        commands.entity(entity).remove::<component::FormationSlot>();
+                    }
+                    do_writer.send(Do(entity, command.state, command.position));
+                }
+            }
+        }
+        // Played back up to the end of the log, trailing checkpoints and the death included.
+        if log.seconds() <= repeat.elapsed {
+            if let Some(_) = opt_enemy {
+                sprite.color = ENEMY_COLOR;
+            } else {
+                sprite.color = DEFAULT_COLOR;
+                commands.entity(entity).insert(Selectable);
+            }
+            log.origin = None;
+            log.checkpoints.clear();
+            log.death = None;
+            log.diverged = false;
+            commands.entity(entity).remove::<component::Echo>();
+            commands.entity(entity).remove::<component::Repeat>();
+        }
+    }
+}
+
+/// Reports a paradox when the echo strays past `PARADOX_DISTANCE`, once each time it does,
+/// like a ghost pushed off its recording.
+fn diverge(paradox_writer: &mut EventWriter<Paradox>, entity: Entity, log: &mut component::CommandLog, divergence: f32) {
+    let diverged = divergence > PARADOX_DISTANCE;
+    if diverged && !log.diverged {
+        paradox_writer.send(Paradox(entity, ParadoxKind::Divergence(divergence)));
+    }
+    log.diverged = diverged;
+}
+
+#[derive(SystemParam)]
+pub struct CommandLogQueries<'w, 's> {
+    original_query: Query<'w, 's, (Entity, &'static mut Sprite, &'static mut Transform, &'static mut component::Velocity, &'static mut component::Facing, &'static mut component::Target, &'static mut component::CurrentState, &'static mut component::CurrentAction, &'static component::CommandLog, Option<&'static component::Enemy>), (Without<component::Ghost>, Without<component::Echo>)>,
+    clone_query: Query<'w, 's, (Entity, &'static component::Unit, &'static component::Kind, &'static component::CommandLog, Option<&'static component::Lineage>, Option<&'static component::Enemy>)>,
+}
+
+pub fn start_repeat(
+    mut spawner: UnitSpawner,
+    mut repeat_reader: EventReader<Repeat>,
+    mut log_queries: CommandLogQueries,
+    generations: Res<GhostGenerations>,
+    round: Res<Round>,
+    ) {
+    for event in repeat_reader.read() {
+        if !event.1 {
+            if let Ok((entity, mut sprite, mut transform, mut velocity, mut facing, mut target, mut state, mut action, log, opt_enemy)) = log_queries.original_query.get_mut(event.0) {
+                if let Some(origin) = &log.origin {
+                    sprite.color = GHOST_COLOR;
+                    transform.translation = origin.position;
+                    velocity.x = 0.0;
+                    velocity.y = 0.0;
+                    facing.value = origin.facing;
+                    target.entity = None;
+                    target.x = origin.position.x;
+                    target.y = origin.position.y;
+                    state.value = State::Idle;
+                    action.value = Action::None;
+                    if let None = opt_enemy {
+                        spawner.commands.entity(entity).remove::<Selected>();
+                        spawner.commands.entity(entity).remove::<Selectable>();
+                    }
+                    spawner.commands.entity(entity).insert(component::Echo);
+                    spawner.commands.entity(entity).insert(component::Repeat { elapsed: 0.0, rate: 1.0, paused: false });
+                }
+            }
+        } else {
+            if let Ok((source, unit, kind, log, opt_lineage, opt_enemy)) = log_queries.clone_query.get(event.0) {
+                if let Some(origin) = &log.origin {
+                    let Some(lineage) = descend(&generations, source, opt_lineage, round.attempts) else {
+                        continue;
+                    };
+                    let Some(parent) = spawner.spawn(&kind.name, unit.owner, origin.position, origin.facing, GHOST_COLOR) else {
+                        continue;
+                    };
+                    spawner.commands.entity(parent).insert((
+                            component::Echo,
+                            component::CommandLog { origin: Some(origin.clone()), commands: log.commands.clone(), checkpoints: log.checkpoints.clone(), death: log.death, diverged: false },
+                            component::Repeat { elapsed: 0.0, rate: 1.0, paused: false },
+                            lineage.clone(),
+                            ));
+
+                    if let Some(_) = opt_enemy {
+                        spawner.commands.entity(parent).insert(component::Enemy);
+                    }
+                    spawn_lineage_ui(&mut spawner.commands, parent, &lineage);
+                }
+            }
+        }
+    }
+}
@@ -1,11 +1,14 @@
 use std::collections::VecDeque;
 
-use bevy::prelude::{Component, Vec2, Entity};
+use bevy::prelude::{Component, Entity, Handle, Vec2};
 use bevy::time::Timer;
+use serde::{Deserialize, Serialize};
 
 use super::action::Action;
+use super::archetype::Archetype;
 use super::State;
-use super::history::Snapshot;
+use super::store::SnapshotStore;
+use super::command_log::{LoggedCommand, Origin};
 
 pub trait AsVec2 {
     fn as_vec2(&self) -> Vec2;
@@ -21,6 +24,13 @@ pub struct Unit {
     pub owner: usize
 }
 
+/// The archetype a unit was built from.
+#[derive(Component)]
+pub struct Kind {
+    pub name: String,
+    pub handle: Handle<Archetype>,
+}
+
 #[derive(Component)]
 pub struct Health {
     pub current: i32,
@@ -42,6 +52,45 @@ pub struct Enemy;
 #[derive(Component)]
 pub struct Ghost;
 
+#[derive(Component)]
+pub struct Echo;
+
+/// How far collisions have pushed a repeating ghost off its recorded path, and where it was
+/// last put back on it.
+#[derive(Component, Default)]
+pub struct Drift {
+    pub offset: Vec2,
+    pub placed: Option<Vec2>,
+}
+
+#[derive(Clone)]
+pub struct Ancestor {
+    pub entity: Entity,
+    pub attempt: u32,
+}
+
+/// Units an echo was cloned from, oldest first. Originals have no lineage.
+#[derive(Component, Clone)]
+pub struct Lineage {
+    pub ancestors: Vec<Ancestor>,
+}
+
+impl Lineage {
+    pub fn generation(&self) -> u32 {
+        self.ancestors.len() as u32
+    }
+
+    pub fn describe(&self) -> String {
+        self.ancestors.iter()
+            .map(|ancestor| format!("{:?} (attempt {})", ancestor.entity, ancestor.attempt))
+            .collect::<Vec<_>>()
+            .join(" > ")
+    }
+}
+
+#[derive(Component)]
+pub struct LineageUi;
+
 #[derive(Component)]
 pub struct Facing {
     pub value: f32
@@ -64,6 +113,58 @@ impl AsVec2 for Velocity {
     }
 }
 
+/// Where an attack-move is headed, kept while the unit stops to fight on the way.
+#[derive(Component)]
+pub struct Destination {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Clone)]
+pub struct QueuedOrder {
+    pub state: State,
+    pub position: Vec2,
+    pub entity: Option<Entity>,
+}
+
+/// Shift-queued orders, issued one at a time whenever the unit falls idle.
+#[derive(Component)]
+pub struct OrderQueue {
+    pub orders: VecDeque<QueuedOrder>,
+}
+
+/// Waypoints around the walls towards `goal`, the target the path was planned for.
+/// Units ordered there as a large group or a formation follow the shared flow field keyed
+/// `flow` instead, and stop anywhere within `arrival` of the goal.
+#[derive(Component)]
+pub struct Path {
+    pub goal: Option<Vec2>,
+    pub waypoints: VecDeque<Vec2>,
+    pub flow: Option<(u32, u32)>,
+    pub arrival: f32,
+}
+
+/// A unit's slot in a formation ordered to `anchor`. The group walks the anchor's flow field
+/// together at `pace`, which holds back whoever gets ahead of the others. Only applies while
+/// the unit is still headed for `position`.
+#[derive(Component, Clone)]
+pub struct FormationSlot {
+    pub anchor: Vec2,
+    pub position: Vec2,
+    pub pace: f32,
+}
+
+/// Route of a patrolling unit, walked point to point and back to the start after the last.
+#[derive(Component, Clone)]
+pub struct Patrol {
+    pub points: Vec<Vec2>,
+    pub next: usize,
+}
+
+/// Holding position: fights whatever comes in range but never leaves its spot.
+#[derive(Component)]
+pub struct HoldPosition;
+
 #[derive(Component)]
 pub struct TurnRate {
     pub value: f32
@@ -87,19 +188,69 @@ impl AsVec2 for Target {
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct History {
-    pub snapshots: VecDeque<Snapshot>
+    pub snapshots: SnapshotStore
+}
+
+impl History {
+    /// Seconds between the first and last snapshot.
+    pub fn seconds(&self) -> f32 {
+        match (self.snapshots.front(), self.snapshots.back()) {
+            (Some(first), Some(last)) => last.timestamp - first.timestamp,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct CommandLog {
+    pub origin: Option<Origin>,
+    pub commands: VecDeque<LoggedCommand>,
+    /// Where the unit was at regular intervals, what an echo is held to between commands.
+    pub checkpoints: VecDeque<(f32, Vec2)>,
+    pub death: Option<f32>,
+    /// Whether the echo replaying the log was last found off its timeline.
+    pub diverged: bool,
+}
+
+impl CommandLog {
+    /// When the log ends: at its last command, its last checkpoint or the unit's death,
+    /// whichever came last.
+    pub fn end(&self) -> Option<f32> {
+        let last_command = self.commands.back().map(|command| command.timestamp);
+        let last_checkpoint = self.checkpoints.back().map(|(timestamp, _)| *timestamp);
+        [last_command, last_checkpoint, self.death].into_iter().flatten().reduce(f32::max)
+    }
+
+    /// Seconds between the origin and the end of the log.
+    pub fn seconds(&self) -> f32 {
+        match (&self.origin, self.end()) {
+            (Some(origin), Some(end)) => end - origin.timestamp,
+            _ => 0.0,
+        }
+    }
 }
 
 #[derive(Component)]
 pub struct Repeat {
-    pub timestamp: f32
+    pub elapsed: f32,
+    pub rate: f32,
+    pub paused: bool
+}
+
+/// Plays a repeating ghost's recording over again. `remaining` counts the plays left, the
+/// current one included, and `None` loops until told otherwise.
+#[derive(Component)]
+pub struct Loop {
+    pub remaining: Option<u32>
 }
 
 #[derive(Component)]
 pub struct Reverse {
-    pub timestamp: f32
+    pub elapsed: f32,
+    pub rate: f32,
+    pub paused: bool
 }
 
 #[derive(Component)]
@@ -0,0 +1,12 @@
+use bevy::prelude::*;
+use super::ChronoEnergy;
+
+pub fn regenerate(
+    mut energy: ResMut<ChronoEnergy>,
+    time: Res<Time>,
+    ) {
+    let (max, regen) = (energy.max, energy.regen);
+    for pool in energy.pools.values_mut() {
+        *pool = (*pool + regen * time.delta_seconds()).min(max);
+    }
+}
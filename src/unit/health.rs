@@ -1,17 +1,20 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}};
 use rand::Rng;
-use super::{component, State};
+use super::{component, history, Paradox, ParadoxKind, State, archetype::Archetype};
 
 pub const HEALTH_BAR_HEIGHT: f32 = 6.0;
 pub const HEALTH_BAR_WIDTH: f32 = 50.0;
 pub const HEALTH_BAR_BORDER: f32 = 2.0;
+const PARADOX_GRACE: f32 = 0.25;
 
 pub fn health(
     mut commands: Commands,
-    mut dying_query: Query<(Entity, &mut Transform, &Children, &component::Health, &mut component::CurrentState, &mut TextureAtlas)>,
+    mut paradox_writer: EventWriter<Paradox>,
+    archetypes: Res<Assets<Archetype>>,
+    mut dying_query: Query<(Entity, &component::Kind, &mut Transform, Option<&Children>, &component::Health, &mut component::CurrentState, &mut TextureAtlas, Option<&component::Repeat>, Option<&component::Reverse>, &component::History, &component::CommandLog, Has<component::Dead>)>,
     mut target_query: Query<&mut component::Target, With<component::Unit>>,
     ) {
-    for (entity, mut transform, children, health, mut state, mut atlas) in dying_query.iter_mut() {
+    for (entity, kind, mut transform, opt_children, health, mut state, mut atlas, opt_repeat, opt_reverse, history, log, dead) in dying_query.iter_mut() {
         if health.current <= 0 {
             let mut rng = rand::thread_rng();
             for mut target in target_query.iter_mut() {
@@ -19,12 +22,22 @@ pub fn health(
                     target.entity = None;
                 }
             }
-            if state.value != State::Dead {
-                state.value = State::Dead;
-                transform.translation.z -= 200.0;
-                atlas.index = rng.gen_range(80..83);
-                for &child in children.iter() {
-                    commands.entity(child).despawn_recursive();
+            if !dead {
+                if (opt_repeat.is_some() || opt_reverse.is_some()) && !recorded_death(opt_repeat, opt_reverse, history, log) {
+                    paradox_writer.send(Paradox(entity, ParadoxKind::Death));
+                }
+                // Ghosts replaying a recorded death already carry the recorded state and frame.
+                if state.value != State::Dead {
+                    state.value = State::Dead;
+                    transform.translation.z -= 200.0;
+                    if let Some(archetype) = archetypes.get(&kind.handle) {
+                        atlas.index = rng.gen_range(archetype.animation.death_first..=archetype.animation.death_last);
+                    }
+                }
+                if let Some(children) = opt_children {
+                    for &child in children.iter() {
+                        commands.entity(child).despawn_recursive();
+                    }
                 }
                 commands.entity(entity).insert(component::Dead);
             }
@@ -32,6 +45,91 @@ pub fn health(
     }
 }
 
+/// Brings a unit that was rewound past its death back onto the battlefield.
+pub fn revive(
+    commands: &mut Commands,
+    entity: Entity,
+    asset_server: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    ) {
+    commands.entity(entity).remove::<component::Dead>();
+    // Back where the recording has it, not sliding on with the speed it died at.
+    commands.entity(entity).insert(component::Velocity { x: 0.0, y: 0.0 });
+    spawn_unit_ui(commands, entity, asset_server, meshes, materials);
+}
+
+/// Gives a unit back the selection circle and health bar it loses when it dies.
+pub fn spawn_unit_ui(
+    commands: &mut Commands,
+    parent: Entity,
+    asset_server: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    ) {
+    let child_texture = asset_server.load::<Image>("selection_circle.png");
+    let child = commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: Color::WHITE,
+                    ..default()
+                },
+                texture: child_texture,
+                transform: Transform::from_xyz(0.0, -25.0, -100.0),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            component::SelectionCircleUi
+            )).id();
+
+    commands.entity(parent).add_child(child);
+
+    let outer_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH + HEALTH_BAR_BORDER, HEALTH_BAR_HEIGHT + HEALTH_BAR_BORDER)));
+    let inner_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)));
+    let outer_color = Color::BLACK;
+    let inner_color = Color::GREEN;
+
+    let outer = commands.spawn((MaterialMesh2dBundle {
+        mesh: outer_shape,
+        material: materials.add(outer_color),
+        transform: Transform::from_xyz(0.0, 50.0, 100.0),
+        ..default()
+    },
+    component::HealthBarUi)).id();
+
+    let inner = commands.spawn((MaterialMesh2dBundle {
+        mesh: inner_shape,
+        material: materials.add(inner_color),
+        transform: Transform::from_xyz(0.0, 50.0, 101.0),
+        ..default()
+    },
+    component::HealthBarAmountUi)).id();
+
+    commands.entity(parent).add_child(outer);
+    commands.entity(parent).add_child(inner);
+}
+
+/// Whether the recording being played back has the unit dead by now.
+fn recorded_death(
+    opt_repeat: Option<&component::Repeat>,
+    opt_reverse: Option<&component::Reverse>,
+    history: &component::History,
+    log: &component::CommandLog,
+    ) -> bool {
+    if let (Some(first), Some(last)) = (history.snapshots.front(), history.snapshots.back()) {
+        let moment = match (opt_repeat, opt_reverse) {
+            (Some(repeat), _) => first.timestamp + repeat.elapsed + PARADOX_GRACE,
+            (_, Some(reverse)) => last.timestamp - reverse.elapsed - PARADOX_GRACE,
+            _ => return false,
+        };
+        return history::sample(&history.snapshots, moment).is_some_and(|snapshot| snapshot.state == State::Dead);
+    }
+    match (&log.origin, log.death, opt_repeat) {
+        (Some(origin), Some(death), Some(repeat)) => death - origin.timestamp <= repeat.elapsed + PARADOX_GRACE,
+        _ => false,
+    }
+}
+
 pub fn health_ui(
     mut query: Query<(&component::Health, &Children), With<component::Unit>>,
     mut bar_query: Query<(&mut Transform, &mut Visibility), (With<component::HealthBarAmountUi>, Without<component::HealthBarUi>)>,
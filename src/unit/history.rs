@@ -1,21 +1,41 @@
-use bevy::{ecs::system::SystemParam, prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}};
-use crate::input::{component::{Selectable, Selected}, Reverse, Repeat};
-use super::{component, UNIT_HEALTH, health::{HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT, HEALTH_BAR_BORDER}};
+use std::{f32::consts::PI, time::Duration};
+use bevy::{ecs::system::SystemParam, prelude::*};
+use serde::{Deserialize, Serialize};
+use crate::bullet::{BulletLog, Fire, Recall};
+use crate::game::{Round, Seek, TimeCursor};
+use crate::AppState;
+use crate::input::{component::Selectable, Reverse, Repeat};
+use super::{component, archetype::UnitSpawner, store::SnapshotStore, GhostGenerations, HistoryBudget, Paradox, ParadoxKind, ParadoxRule, ReverseFire, DEFAULT_COLOR, ENEMY_COLOR, GHOST_COLOR, PARADOX_DISTANCE, UNIT_HEALTH, action::{Action, MUZZLE_DISTANCE}, health::revive, lineage::{descend, spawn_lineage_ui}};
 
-const GHOST_COLOR: Color = Color::rgba(0.5, 0.5, 1.0, 0.3);
-const ENEMY_COLOR: Color = Color::RED;
-const DEFAULT_COLOR: Color = Color::WHITE;
+const LOOP_COLOR: Color = Color::rgba(0.8, 0.4, 1.0, 0.4);
+/// How far playback may put a unit from where a folded snapshot had it.
+const POSITION_TOLERANCE: f32 = 0.5;
+/// How far playback may turn a unit from where a folded snapshot had it, in radians.
+const ANGLE_TOLERANCE: f32 = 0.01;
 
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Snapshot {
     pub atlas_index: usize,
-    pub action: super::action::Action,
+    pub action: Action,
     pub state: super::State,
     pub timestamp: f32,
     pub position: Vec3,
     pub facing: f32,
     pub direction: f32,
+    #[serde(default = "full_health")]
+    pub health: i32,
+    #[serde(default)]
+    pub dead: bool,
+}
+
+fn full_health() -> i32 {
+    UNIT_HEALTH
+}
+
+fn muzzle(snapshot: &Snapshot) -> (Vec2, f32) {
+    let forward = Vec2::new(snapshot.facing.cos(), snapshot.facing.sin());
+    (snapshot.position.xy() + forward * MUZZLE_DISTANCE, snapshot.facing - (PI / 2.0))
 }
 
 pub fn round_end(
@@ -31,61 +51,377 @@ pub fn round_repeat(
     mut commands: Commands,
     mut writer: EventWriter<Repeat>,
     query: Query<Entity, (With<component::History>, With<component::Unit>, With<component::RespawnNextRound>)>,
+    mut stale_query: Query<(&mut component::History, &mut component::CommandLog), (With<component::Unit>, Without<component::RespawnNextRound>, Without<component::Repeat>, Without<component::Reverse>)>,
     ) {
     for entity in query.iter() {
         commands.entity(entity).remove::<component::RespawnNextRound>();
         writer.send(Repeat(entity, false));
     }
+    // Timestamps are relative to the round start, whatever is not replayed starts over.
+    for (mut history, mut log) in stale_query.iter_mut() {
+        history.snapshots.clear();
+        log.origin = None;
+        log.commands.clear();
+        log.checkpoints.clear();
+        log.death = None;
+        log.diverged = false;
+    }
 }
 
 pub fn track_history(
-    mut queue: Query<(&mut component::History, &TextureAtlas, &Transform, &component::Facing, &component::CurrentState, &component::CurrentAction), (With<component::Unit>, Without<component::Repeat>, Without<component::Reverse>)>,
-    time: Res<Time>,
+    mut queue: Query<(&mut component::History, &TextureAtlas, &Transform, &component::Facing, &component::CurrentState, &component::CurrentAction, &component::Health, Has<component::Dead>), (With<component::Unit>, Without<component::Repeat>, Without<component::Reverse>)>,
+    budget: Res<HistoryBudget>,
+    cursor: Res<TimeCursor>,
     ) {
-    for (mut history, atlas, transform, facing, state, action) in queue.iter_mut() {
+    for (mut history, atlas, transform, facing, state, action, health, dead) in queue.iter_mut() {
         let z = transform.rotation.to_euler(EulerRot::XYZ).2;
-        history.snapshots.push_back(Snapshot {
+        record(&mut history.snapshots, budget.snapshots(), Snapshot {
             atlas_index: atlas.index,
             action: action.value,
             state: state.value,
-            timestamp: time.elapsed_seconds(),
+            timestamp: cursor.step,
             position: transform.translation,
             facing: facing.value,
             direction: z,
+            health: health.current,
+            dead,
         });
     }
 }
 
+/// Rates of change of position, facing and direction from a keyframe that keep every
+/// snapshot folded since then within tolerance of what playback interpolates.
+#[derive(Clone, Copy)]
+pub struct Corridor {
+    low: [f32; 5],
+    high: [f32; 5],
+}
+
+impl Corridor {
+    pub const OPEN: Corridor = Corridor {
+        low: [f32::NEG_INFINITY; 5],
+        high: [f32::INFINITY; 5],
+    };
+
+    /// Narrows the corridor to the rates that still pass within tolerance of `snapshot`.
+    fn narrow(mut self, keyframe: &Snapshot, snapshot: &Snapshot) -> Option<Corridor> {
+        let span = snapshot.timestamp - keyframe.timestamp;
+        if span <= 0.0 {
+            return None;
+        }
+        let rates = rates(keyframe, snapshot, span);
+        let tolerances = [POSITION_TOLERANCE, POSITION_TOLERANCE, POSITION_TOLERANCE, ANGLE_TOLERANCE, ANGLE_TOLERANCE];
+        for field in 0..5 {
+            self.low[field] = self.low[field].max(rates[field] - tolerances[field] / span);
+            self.high[field] = self.high[field].min(rates[field] + tolerances[field] / span);
+        }
+        Some(self)
+    }
+
+    fn contains(&self, keyframe: &Snapshot, snapshot: &Snapshot) -> bool {
+        let span = snapshot.timestamp - keyframe.timestamp;
+        span > 0.0 && rates(keyframe, snapshot, span).iter()
+            .zip(self.low.iter().zip(self.high.iter()))
+            .all(|(rate, (low, high))| rate >= low && rate <= high)
+    }
+}
+
+fn rates(keyframe: &Snapshot, snapshot: &Snapshot, span: f32) -> [f32; 5] {
+    let offset = snapshot.position - keyframe.position;
+    [
+        offset.x,
+        offset.y,
+        offset.z,
+        angle_difference(keyframe.facing, snapshot.facing),
+        angle_difference(keyframe.direction, snapshot.direction),
+    ].map(|change| change / span)
+}
+
+/// Appends a snapshot, dropping the oldest ones once `capacity` is reached. Position, facing
+/// and direction are interpolated on playback, so while nothing else changes the last
+/// snapshot is folded into the new one as long as interpolating from the keyframe before it
+/// still reproduces every folded snapshot within tolerance. Standing still, walking a
+/// straight line or turning at a steady rate keeps only the first and last snapshot.
+pub fn record(snapshots: &mut SnapshotStore, capacity: usize, snapshot: Snapshot) {
+    let len = snapshots.len();
+    if len >= 2 && same_frame(&snapshots[len - 2], &snapshots[len - 1]) && same_frame(&snapshots[len - 1], &snapshot) {
+        let keyframe = &snapshots[len - 2];
+        let narrowed = snapshots.corridor().and_then(|corridor| corridor.narrow(keyframe, &snapshots[len - 1]));
+        if let Some(corridor) = narrowed {
+            if corridor.contains(keyframe, &snapshot) {
+                if let Some(last) = snapshots.back_mut() {
+                    *last = snapshot;
+                }
+                snapshots.set_corridor(Some(corridor));
+                return;
+            }
+        }
+    }
+    snapshots.push_back(snapshot);
+    snapshots.set_corridor(Some(Corridor::OPEN));
+    while snapshots.len() > capacity {
+        snapshots.pop_front();
+    }
+}
+
+/// Whether two snapshots only differ in what playback interpolates.
+fn same_frame(a: &Snapshot, b: &Snapshot) -> bool {
+    a.atlas_index == b.atlas_index
+        && a.action == b.action
+        && a.state == b.state
+        && a.health == b.health
+        && a.dead == b.dead
+}
+
 pub fn repeat_history(
     mut commands: Commands,
-    mut queue: Query<(Entity, &mut Sprite, &mut Transform, &mut component::Facing, &mut component::History, &mut TextureAtlas, &mut component::CurrentState, &mut component::CurrentAction, Option<&component::Repeat>, Option<&component::Reverse>, Option<&component::Enemy>), (With<component::Unit>, With<component::Ghost>)>,
+    mut fire_writer: EventWriter<Fire>,
+    mut recall_writer: EventWriter<Recall>,
+    mut paradox_writer: EventWriter<Paradox>,
+    reverse_fire: Res<ReverseFire>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    lineage_query: Query<&component::Lineage>,
+    bullet_log: Res<BulletLog>,
+    mut queue: Query<(Entity, &component::Unit, &mut Sprite, &mut Transform, &mut component::Facing, &mut component::History, &mut TextureAtlas, &mut component::CurrentState, &mut component::CurrentAction, &mut component::Health, Has<component::Dead>, (Option<&mut component::Repeat>, Option<&mut component::Reverse>, Option<&mut component::Loop>, Option<&mut component::Drift>), Option<&component::Enemy>), With<component::Ghost>>,
+    time: Res<Time>,
     ) {
-    for (entity, mut sprite, mut transform, mut facing, mut history, mut atlas, mut state, mut action, opt_repeat, opt_reverse, opt_enemy) in queue.iter_mut() {
-        if let Some(_) = opt_repeat {
-            if let Some(snapshot) = history.snapshots.pop_front() {
-                let mut historical_transform = Transform::from_translation(snapshot.position);
-                historical_transform.rotate_z(snapshot.direction);
-                *transform = historical_transform;
-                state.value = snapshot.state;
-                action.value = snapshot.action;
-                atlas.index = snapshot.atlas_index;
-                facing.value = snapshot.facing;
-                if action.value == super::action::Action::Attack {
-                    warn!("Repeat Attack");
+    for (entity, unit, mut sprite, mut transform, mut facing, mut history, mut atlas, mut state, mut action, mut health, dead, (opt_repeat, opt_reverse, opt_loop, opt_drift), opt_enemy) in queue.iter_mut() {
+        let (Some(first_timestamp), Some(last_timestamp)) = (history.snapshots.front().map(|snapshot| snapshot.timestamp), history.snapshots.back().map(|snapshot| snapshot.timestamp)) else {
+            finish_playback(&mut commands, entity, &mut sprite, opt_enemy);
+            commands.entity(entity).remove::<component::Repeat>();
+            commands.entity(entity).remove::<component::Reverse>();
+            continue;
+        };
+        let reversing = opt_reverse.is_some();
+        // A repeating ghost that died stays where it fell, only rewinding brings it back.
+        if dead && !reversing {
+            continue;
+        }
+        let mut finished = false;
+        let mut moment = first_timestamp;
+        if let Some(mut repeat) = opt_repeat {
+            let previous = first_timestamp + repeat.elapsed;
+            if !repeat.paused {
+                repeat.elapsed += time.delta_seconds() * repeat.rate;
+            }
+            moment = first_timestamp + repeat.elapsed;
+            for shot in shots(&history.snapshots, previous, moment) {
+                let (muzzle, rotation) = muzzle(shot);
+                fire_writer.send(Fire(entity, unit.owner, muzzle, rotation));
+            }
+            let mut looping = opt_loop.is_some();
+            if let Some(mut repeat_loop) = opt_loop {
+                let span = last_timestamp - first_timestamp;
+                // A fast enough playback can run through the recording more than once a frame.
+                while moment >= last_timestamp && span > 0.0 {
+                    if let Some(remaining) = repeat_loop.remaining.as_mut() {
+                        *remaining = remaining.saturating_sub(1);
+                        if *remaining == 0 {
+                            looping = false;
+                            commands.entity(entity).remove::<component::Loop>();
+                            break;
+                        }
+                    }
+                    // Keep the overshoot so a loop stays in step with the clock.
+                    repeat.elapsed -= span;
+                    moment = first_timestamp + repeat.elapsed;
+                    for shot in shots(&history.snapshots, first_timestamp, moment) {
+                        let (muzzle, rotation) = muzzle(shot);
+                        fire_writer.send(Fire(entity, unit.owner, muzzle, rotation));
+                    }
                 }
+            }
+            if moment >= last_timestamp {
+                finished = true;
+                commands.entity(entity).remove::<component::Repeat>();
+            } else if looping {
+                sprite.color = LOOP_COLOR;
             } else {
-                if let Some(_) = opt_enemy {
-                    sprite.color = ENEMY_COLOR;
+                sprite.color = GHOST_COLOR;
+            }
+        }
+        if let Some(mut reverse) = opt_reverse {
+            let previous = last_timestamp - reverse.elapsed;
+            if !reverse.paused {
+                reverse.elapsed += time.delta_seconds() * reverse.rate;
+            }
+            moment = last_timestamp - reverse.elapsed;
+            if *reverse_fire == ReverseFire::Recall {
+                // A clone retraces the shots of the unit it was cloned from.
+                let shooter = lineage_query.get(entity).ok()
+                    .and_then(|lineage| lineage.ancestors.last())
+                    .map_or(entity, |ancestor| ancestor.entity);
+                if bullet_log.fired_by(shooter) {
+                    for index in bullet_log.ended_between(shooter, moment, previous, last_timestamp) {
+                        recall_writer.send(Recall(unit.owner, Vec2::ZERO, 0.0, Some(index)));
+                    }
                 } else {
-                    sprite.color = DEFAULT_COLOR;
-                    commands.entity(entity).insert(Selectable);
+                    for shot in shots(&history.snapshots, moment, previous) {
+                        let (muzzle, rotation) = muzzle(shot);
+                        recall_writer.send(Recall(unit.owner, muzzle, rotation, None));
+                    }
+                }
+            }
+            if moment <= first_timestamp {
+                finished = true;
+                commands.entity(entity).remove::<component::Reverse>();
+            }
+        }
+        if let Some(snapshot) = sample(&history.snapshots, moment) {
+            let mut position = snapshot.position;
+            if let Some(mut drift) = opt_drift {
+                // Whatever moved the ghost since it was last placed pushed it off its recording.
+                let before = drift.offset.length();
+                if let Some(placed) = drift.placed {
+                    drift.offset += transform.translation.xy() - placed;
+                }
+                let divergence = drift.offset.length();
+                if before <= PARADOX_DISTANCE && divergence > PARADOX_DISTANCE {
+                    paradox_writer.send(Paradox(entity, ParadoxKind::Divergence(divergence)));
+                }
+                position += drift.offset.extend(0.0);
+                drift.placed = Some(position.xy());
+            }
+            let mut historical_transform = Transform::from_translation(position);
+            historical_transform.rotate_z(snapshot.direction);
+            *transform = historical_transform;
+            state.value = snapshot.state;
+            action.value = snapshot.action;
+            atlas.index = snapshot.atlas_index;
+            facing.value = snapshot.facing;
+            // Only rewinding undoes damage, a repeating ghost takes hits and dies like anyone.
+            if reversing {
+                health.current = snapshot.health;
+                if dead && !snapshot.dead {
+                    revive(&mut commands, entity, &asset_server, &mut meshes, &mut materials);
+                }
+            } else if snapshot.dead {
+                // The recorded death still happens on time.
+                health.current = health.current.min(0);
+            }
+        }
+        if finished {
+            // A finished playback has used its recording up, the unit starts a fresh one.
+            history.snapshots.clear();
+            finish_playback(&mut commands, entity, &mut sprite, opt_enemy);
+        }
+    }
+}
+
+fn finish_playback(
+    commands: &mut Commands,
+    entity: Entity,
+    sprite: &mut Sprite,
+    opt_enemy: Option<&component::Enemy>,
+    ) {
+    if let Some(_) = opt_enemy {
+        sprite.color = ENEMY_COLOR;
+    } else {
+        sprite.color = DEFAULT_COLOR;
+        commands.entity(entity).insert(Selectable);
+    }
+    commands.entity(entity).remove::<component::Ghost>();
+    commands.entity(entity).remove::<component::Loop>();
+    commands.entity(entity).remove::<component::Drift>();
+    // Playback put the unit where the recording ended, it sets off from a standstill.
+    commands.entity(entity).insert(component::Velocity { x: 0.0, y: 0.0 });
+}
+
+/// Returns the recorded state at `moment`, with position and facing interpolated between the
+/// neighbouring snapshots and everything else taken from the earlier one.
+pub fn sample(snapshots: &SnapshotStore, moment: f32) -> Option<Snapshot> {
+    let index = snapshots.partition_point(|snapshot| snapshot.timestamp <= moment).saturating_sub(1);
+    let current = snapshots.get(index)?;
+    let Some(next) = snapshots.get(index + 1) else {
+        return Some(current.clone());
+    };
+    let span = next.timestamp - current.timestamp;
+    let t = if span > 0.0 { ((moment - current.timestamp) / span).clamp(0.0, 1.0) } else { 0.0 };
+    Some(Snapshot {
+        timestamp: moment,
+        position: current.position.lerp(next.position, t),
+        facing: lerp_angle(current.facing, next.facing, t),
+        direction: lerp_angle(current.direction, next.direction, t),
+        ..current.clone()
+    })
+}
+
+/// Snapshots in `(from, to]` on which a shot left the barrel, i.e. the last tick of each
+/// run of Action::Attack.
+fn shots(snapshots: &SnapshotStore, from: f32, to: f32) -> Vec<&Snapshot> {
+    let start = snapshots.partition_point(|snapshot| snapshot.timestamp <= from);
+    let end = snapshots.partition_point(|snapshot| snapshot.timestamp <= to);
+    (start..end)
+        .filter(|&index| snapshots[index].action == Action::Attack)
+        .filter(|&index| snapshots.get(index + 1).is_none_or(|next| next.action != Action::Attack))
+        .map(|index| &snapshots[index])
+        .collect()
+}
+
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    from + angle_difference(from, to) * t
+}
+
+fn angle_difference(from: f32, to: f32) -> f32 {
+    (to - from + PI).rem_euclid(2.0 * PI) - PI
+}
+
+pub fn resolve_paradox(
+    mut commands: Commands,
+    mut paradox_reader: EventReader<Paradox>,
+    mut app_state: ResMut<NextState<AppState>>,
+    paradox_rule: Res<ParadoxRule>,
+    ) {
+    for event in paradox_reader.read() {
+        warn!("Paradox {:?} on {:?}", event.1, event.0);
+        match *paradox_rule {
+            ParadoxRule::Ignore => {}
+            ParadoxRule::Collapse => {
+                if let Some(entity) = commands.get_entity(event.0) {
+                    entity.despawn_recursive();
+                }
+            }
+            ParadoxRule::FailRound => {
+                app_state.set(AppState::RoundEnd);
+            }
+        }
+    }
+}
+
+pub fn seek(
+    mut commands: Commands,
+    mut seek_reader: EventReader<Seek>,
+    mut cursor: ResMut<TimeCursor>,
+    mut round: ResMut<Round>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut query: Query<(Entity, &mut component::History, &mut component::CommandLog, &mut Transform, &mut component::Facing, &mut TextureAtlas, &mut component::CurrentState, &mut component::CurrentAction, &mut component::Target, &mut component::Health, &mut component::Velocity, Has<component::Dead>), (With<component::Unit>, Without<component::Repeat>, Without<component::Reverse>)>,
+    mut ghost_query: Query<(Option<&mut component::Repeat>, Option<&mut component::Reverse>), With<component::Ghost>>,
+    ) {
+    for event in seek_reader.read() {
+        // Everything after the moment is discarded and the round resumes from there.
+        let moment = event.0;
+        // Ghosts keep their recordings and play them back from where they were at the moment.
+        let rewound = cursor.current - moment;
+        for (opt_repeat, opt_reverse) in ghost_query.iter_mut() {
+            if let Some(mut repeat) = opt_repeat {
+                if !repeat.paused {
+                    repeat.elapsed = (repeat.elapsed - rewound * repeat.rate).max(0.0);
+                }
+            }
+            if let Some(mut reverse) = opt_reverse {
+                if !reverse.paused {
+                    reverse.elapsed = (reverse.elapsed - rewound * reverse.rate).max(0.0);
                 }
-                commands.entity(entity).remove::<component::Ghost>();
-                commands.entity(entity).remove::<component::Repeat>();
             }
         }
-        if let Some(_) = opt_reverse {
-            if let Some(snapshot) = history.snapshots.pop_back() {
+        for (entity, mut history, mut log, mut transform, mut facing, mut atlas, mut state, mut action, mut target, mut health, mut velocity, dead) in query.iter_mut() {
+            let kept = history.snapshots.partition_point(|snapshot| snapshot.timestamp <= moment);
+            history.snapshots.truncate(kept);
+            if let Some(snapshot) = history.snapshots.back() {
                 let mut historical_transform = Transform::from_translation(snapshot.position);
                 historical_transform.rotate_z(snapshot.direction);
                 *transform = historical_transform;
@@ -93,137 +429,80 @@ pub fn repeat_history(
                 action.value = snapshot.action;
                 atlas.index = snapshot.atlas_index;
                 facing.value = snapshot.facing;
-                if action.value == super::action::Action::Attack {
-                    warn!("Reverse Attack");
-                }
-            } else {
-                if let Some(_) = opt_enemy {
-                    sprite.color = ENEMY_COLOR;
-                } else {
-                    sprite.color = DEFAULT_COLOR;
-                    commands.entity(entity).insert(Selectable);
+                target.entity = None;
+                target.x = snapshot.position.x;
+                target.y = snapshot.position.y;
+                velocity.x = 0.0;
+                velocity.y = 0.0;
+                health.current = snapshot.health;
+                if dead && !snapshot.dead {
+                    revive(&mut commands, entity, &asset_server, &mut meshes, &mut materials);
                 }
-                commands.entity(entity).remove::<component::Ghost>();
-                commands.entity(entity).remove::<component::Reverse>();
+            }
+            log.commands.retain(|command| command.timestamp <= moment);
+            log.checkpoints.retain(|(timestamp, _)| *timestamp <= moment);
+            if log.death.is_some_and(|death| death > moment) {
+                log.death = None;
             }
         }
+        cursor.current = moment;
+        cursor.step = moment;
+        round.timer.set_elapsed(Duration::from_secs_f32(event.0));
     }
 }
 
 #[derive(SystemParam)]
 pub struct HistoryQueries<'w, 's> {
-    original_query: Query<'w, 's, (Entity, &'static mut Sprite, &'static mut component::Target, &'static component::History, Option<&'static component::Enemy>)>,
-    clone_query: Query<'w, 's, (&'static component::Unit, &'static component::History, &'static component::Radius, &'static component::TurnRate, &'static component::MoveSpeed, &'static component::Facing, &'static component::CurrentState, &'static component::CurrentAction, &'static component::Attack, &'static component::AnimationIndices, &'static component::AnimationTimer, &'static component::Health, Option<&'static component::Enemy>)>,
+    original_query: Query<'w, 's, (Entity, &'static mut Sprite, &'static mut component::Target, &'static component::History), (Without<component::Ghost>, Without<component::Echo>)>,
+    clone_query: Query<'w, 's, (Entity, &'static component::Unit, &'static component::Kind, &'static component::History, &'static component::Facing, &'static component::CurrentState, &'static component::CurrentAction, &'static component::Health, Option<&'static component::Lineage>, Option<&'static component::Enemy>, Option<&'static component::Patrol>)>,
 }
 
 pub fn start_reverse(
-    mut commands: Commands,
+    mut spawner: UnitSpawner,
     mut reverse_reader: EventReader<Reverse>,
-    asset_server: Res<AssetServer>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
     mut history_queries: HistoryQueries,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    time: Res<Time>,
+    generations: Res<GhostGenerations>,
+    round: Res<Round>,
     ) {
     for event in reverse_reader.read() {
         if !event.1 {
-            if let Ok((entity, mut sprite, mut target, history, opt_enemy)) = history_queries.original_query.get_mut(event.0) {
+            if let Ok((entity, mut sprite, mut target, history)) = history_queries.original_query.get_mut(event.0) {
                 sprite.color = GHOST_COLOR;
                 if let Some(first_snapshot) = history.snapshots.front() {
                     target.x = first_snapshot.position.x;
                     target.y = first_snapshot.position.y;
-                    if let None = opt_enemy {
-                        commands.entity(entity).remove::<Selected>();
-                        commands.entity(entity).remove::<Selectable>();
-                    }
-                    commands.entity(entity).insert(component::Ghost);
-                    commands.entity(entity).insert(component::Reverse { timestamp: time.elapsed_seconds() });
+                    spawner.commands.entity(entity).insert(component::Ghost);
+                    spawner.commands.entity(entity).insert(component::Reverse { elapsed: 0.0, rate: 1.0, paused: false });
                 }
             }
         } else {
-            if let Ok((unit, history, radius, turn_rate, move_speed, facing, state, action, attack, anim_indices, anim_timer, health, opt_enemy)) = history_queries.clone_query.get(event.0) {
+            if let Ok((source, unit, kind, history, facing, state, action, health, opt_lineage, opt_enemy, _)) = history_queries.clone_query.get(event.0) {
+                let Some(lineage) = descend(&generations, source, opt_lineage, round.attempts) else {
+                    continue;
+                };
                 if let Some(last_snapshot) = history.snapshots.back() {
                     if let Some(first_snapshot) = history.snapshots.front() {
-                        let texture = asset_server.load::<Image>("marine.png");
-                        let layout = TextureAtlasLayout::from_grid(Vec2::new(100.0, 100.0), 8, 11, None, None);
-                        let texture_atlas_layout = texture_atlas_layouts.add(layout);
-                        let spawn_transform = Transform::from_xyz(last_snapshot.position.x, last_snapshot.position.y, 0.0);
-                        let parent = commands.spawn((SpriteSheetBundle {
-                            sprite: Sprite {
-                                color: GHOST_COLOR,
-                                ..default()
-                            },
-                            texture,
-                            atlas: TextureAtlas {
-                                layout: texture_atlas_layout,
-                                index: 0,
-                            },
-                            transform: spawn_transform,
-                            ..default()
-                        },
-                        component::Unit { owner: unit.owner },
-                        ))
-                        .insert((
-                        component::Ghost,
-                        component::Radius { value: radius.value },
-                        component::Velocity { x: 0.0, y: 0.0 },
-                        component::MoveSpeed { value: move_speed.value },
-                        component::Facing { value: facing.value },
-                        component::TurnRate { value: turn_rate.value },
-                        component::Target { entity: None, x: first_snapshot.position.x, y: first_snapshot.position.y},
-                        component::Attack { range: attack.range, timer: attack.timer.clone() },
-                        component::CurrentAction { value: action.value },
-                        component::CurrentState { value: state.value },
-                        component::History { snapshots: history.snapshots.clone() },
-                        component::Health { current: health.current, max: health.max },
-                        component::AnimationIndices { current: anim_indices.current, first: anim_indices.first, last: anim_indices.last },
-                        component::AnimationTimer { timer: anim_timer.timer.clone() },
-                        component::Reverse { timestamp: time.elapsed_seconds() },
-                        )).id();
+                        let spawn_position = Vec3::new(last_snapshot.position.x, last_snapshot.position.y, 0.0);
+                        let Some(parent) = spawner.spawn(&kind.name, unit.owner, spawn_position, facing.value, GHOST_COLOR) else {
+                            continue;
+                        };
+                        spawner.commands.entity(parent).insert((
+                                component::Ghost,
+                                component::Target { entity: None, x: first_snapshot.position.x, y: first_snapshot.position.y},
+                                component::CurrentAction { value: action.value },
+                                component::CurrentState { value: state.value },
+                                component::History { snapshots: history.snapshots.clone() },
+                                component::Health { current: health.current, max: health.max },
+                                component::Reverse { elapsed: 0.0, rate: 1.0, paused: false },
+                                lineage.clone(),
+                                ));
 
                         if let Some(_) = opt_enemy {
-                            commands.entity(parent).insert(component::Enemy);
+                            spawner.commands.entity(parent).insert(component::Enemy);
+                        } else {
+                            spawner.commands.entity(parent).insert(Selectable);
                         }
-
-                        let child_texture = asset_server.load::<Image>("selection_circle.png");
-                        let child = commands.spawn(
-                            SpriteBundle {
-                                sprite: Sprite {
-                                    color: Color::WHITE,
-                                    ..default()
-                                },
-                                texture: child_texture,
-                                transform: Transform::from_xyz(0.0, -25.0, -100.0),
-                                visibility: Visibility::Hidden,
-                                ..default()
-                            }).id();
-
-                        commands.entity(parent).add_child(child);
-
-                        let outer_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH + HEALTH_BAR_BORDER, HEALTH_BAR_HEIGHT + HEALTH_BAR_BORDER)));
-                        let inner_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)));
-                        let outer_color = Color::BLACK;
-                        let inner_color = Color::GREEN;
-
-                        let outer = commands.spawn((MaterialMesh2dBundle {
-                            mesh: outer_shape,
-                            material: materials.add(outer_color),
-                            transform: Transform::from_xyz(0.0, 50.0, 100.0),
-                            ..default()
-                        },
-                        component::HealthBarUi)).id();
-
-                        let inner = commands.spawn((MaterialMesh2dBundle {
-                            mesh: inner_shape,
-                            material: materials.add(inner_color),
-                            transform: Transform::from_xyz(0.0, 50.0, 101.0),
-                            ..default()
-                        },
-                        component::HealthBarAmountUi)).id();
-
-                        commands.entity(parent).add_child(outer);
-                        commands.entity(parent).add_child(inner);
+                        spawn_lineage_ui(&mut spawner.commands, parent, &lineage);
                     }
                 }
             }
@@ -232,116 +511,232 @@ pub fn start_reverse(
 }
 
 pub fn start_repeat(
-    mut commands: Commands,
+    mut spawner: UnitSpawner,
     mut repeat_reader: EventReader<Repeat>,
-    asset_server: Res<AssetServer>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
     mut history_queries: HistoryQueries,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    time: Res<Time>,
+    generations: Res<GhostGenerations>,
+    round: Res<Round>,
     ) {
     for event in repeat_reader.read() {
         if !event.1 {
-            if let Ok((entity, mut sprite, mut target, history, opt_enemy)) = history_queries.original_query.get_mut(event.0) {
+            if let Ok((entity, mut sprite, mut target, history)) = history_queries.original_query.get_mut(event.0) {
                 sprite.color = GHOST_COLOR;
                 if let Some(last_snapshot) = history.snapshots.back() {
                 target.x = last_snapshot.position.x;
                 target.y = last_snapshot.position.y;
-                if let None = opt_enemy {
-                    commands.entity(entity).remove::<Selected>();
-                    commands.entity(entity).remove::<Selectable>();
-                }
-                commands.entity(entity).insert(component::Ghost);
-                commands.entity(entity).insert(component::Repeat{timestamp: time.elapsed_seconds()});
+                spawner.commands.entity(entity).insert(component::Ghost);
+                spawner.commands.entity(entity).insert(component::Repeat { elapsed: 0.0, rate: 1.0, paused: false });
+                spawner.commands.entity(entity).insert(component::Drift::default());
                 }
             }
         } else {
-            if let Ok((unit, history, radius, turn_rate, move_speed, facing, state, action, attack, anim_indices, anim_timer, health, opt_enemy)) = history_queries.clone_query.get(event.0) {
+            if let Ok((source, unit, kind, history, facing, state, action, _, opt_lineage, opt_enemy, opt_patrol)) = history_queries.clone_query.get(event.0) {
+                let Some(lineage) = descend(&generations, source, opt_lineage, round.attempts) else {
+                    continue;
+                };
                 if let Some(first_snapshot) = history.snapshots.front() {
                     if let Some(last_snapshot) = history.snapshots.back() {
-                        let texture = asset_server.load::<Image>("marine.png");
-                        let layout = TextureAtlasLayout::from_grid(Vec2::new(100.0, 100.0), 8, 11, None, None);
-                        let texture_atlas_layout = texture_atlas_layouts.add(layout);
-                        let spawn_transform = Transform::from_xyz(first_snapshot.position.x, first_snapshot.position.y, 0.0);
-                        let parent = commands.spawn((SpriteSheetBundle {
-                            sprite: Sprite {
-                                color: GHOST_COLOR,
-                                ..default()
-                            },
-                            texture,
-                            atlas: TextureAtlas {
-                                layout: texture_atlas_layout,
-                                index: 0,
-                            },
-                            transform: spawn_transform,
-                            ..default()
-                        },
-                        component::Unit { owner: unit.owner },
-                        ))
-                        .insert((
-                        component::Ghost,
-                        component::Radius { value: radius.value },
-                        component::Velocity { x: 0.0, y: 0.0 },
-                        component::MoveSpeed { value: move_speed.value },
-                        component::Facing { value: facing.value },
-                        component::TurnRate { value: turn_rate.value },
-                        component::Target { entity: None, x: last_snapshot.position.x, y: last_snapshot.position.y},
-                        component::Attack { range: attack.range, timer: attack.timer.clone() },
-                        component::CurrentAction { value: action.value },
-                        component::CurrentState { value: state.value },
-                        component::History { snapshots: history.snapshots.clone() },
-                        component::Health { current: UNIT_HEALTH, max: UNIT_HEALTH },
-                        component::AnimationIndices { current: anim_indices.current, first: anim_indices.first, last: anim_indices.last },
-                        component::AnimationTimer { timer: anim_timer.timer.clone() },
-                        component::Repeat { timestamp: time.elapsed_seconds() },
-                        )).id();
+                        let spawn_position = Vec3::new(first_snapshot.position.x, first_snapshot.position.y, 0.0);
+                        let Some(parent) = spawner.spawn(&kind.name, unit.owner, spawn_position, facing.value, GHOST_COLOR) else {
+                            continue;
+                        };
+                        spawner.commands.entity(parent).insert((
+                                component::Ghost,
+                                component::Target { entity: None, x: last_snapshot.position.x, y: last_snapshot.position.y},
+                                component::CurrentAction { value: action.value },
+                                component::CurrentState { value: state.value },
+                                component::History { snapshots: history.snapshots.clone() },
+                                component::Repeat { elapsed: 0.0, rate: 1.0, paused: false },
+                                component::Drift::default(),
+                                lineage.clone(),
+                                ));
+                        // The clone ends where its source is now, so it carries on the same patrol.
+                        if let Some(patrol) = opt_patrol {
+                            spawner.commands.entity(parent).insert(patrol.clone());
+                        }
 
                         if let Some(_) = opt_enemy {
-                            commands.entity(parent).insert(component::Enemy);
+                            spawner.commands.entity(parent).insert(component::Enemy);
+                        } else {
+                            spawner.commands.entity(parent).insert(Selectable);
                         }
-
-                        let child_texture = asset_server.load::<Image>("selection_circle.png");
-                        let child = commands.spawn(
-                            SpriteBundle {
-                                sprite: Sprite {
-                                    color: Color::WHITE,
-                                    ..default()
-                                },
-                                texture: child_texture,
-                                transform: Transform::from_xyz(0.0, -25.0, -100.0),
-                                visibility: Visibility::Hidden,
-                                ..default()
-                            }).id();
-
-                        commands.entity(parent).add_child(child);
-
-                        let outer_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH + HEALTH_BAR_BORDER, HEALTH_BAR_HEIGHT + HEALTH_BAR_BORDER)));
-                        let inner_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)));
-                        let outer_color = Color::BLACK;
-                        let inner_color = Color::GREEN;
-
-                        let outer = commands.spawn((MaterialMesh2dBundle {
-                            mesh: outer_shape,
-                            material: materials.add(outer_color),
-                            transform: Transform::from_xyz(0.0, 50.0, 100.0),
-                            ..default()
-                        },
-                        component::HealthBarUi)).id();
-
-                        let inner = commands.spawn((MaterialMesh2dBundle {
-                            mesh: inner_shape,
-                            material: materials.add(inner_color),
-                            transform: Transform::from_xyz(0.0, 50.0, 101.0),
-                            ..default()
-                        },
-                        component::HealthBarAmountUi)).id();
-
-                        commands.entity(parent).add_child(outer);
-                        commands.entity(parent).add_child(inner);
+                        spawn_lineage_ui(&mut spawner.commands, parent, &lineage);
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two runs of Action::Attack: ticks 2-3 and tick 6, one shot leaving the barrel at the
+    /// end of each.
+    fn attack_runs() -> SnapshotStore {
+        (0..=10).map(|tick| Snapshot {
+            atlas_index: 0,
+            action: if tick == 2 || tick == 3 || tick == 6 { Action::Attack } else { Action::None },
+            state: super::super::State::Idle,
+            timestamp: tick as f32 * 0.1,
+            position: Vec3::new(tick as f32 * 10.0, 0.0, 0.0),
+            facing: 0.0,
+            direction: 0.0,
+            health: UNIT_HEALTH,
+            dead: false,
+        }).collect::<Vec<Snapshot>>().into()
+    }
+
+    /// Plays the recording back for `seconds` in one update and returns the events it sent.
+    fn play(reverse_fire: ReverseFire, reversing: bool, opt_loop: Option<component::Loop>, seconds: u64) -> (Vec<Vec2>, Vec<Recall>) {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .init_asset::<Mesh>()
+            .init_asset::<ColorMaterial>()
+            .insert_resource(Time::<()>::default())
+            .insert_resource(reverse_fire)
+            .insert_resource(BulletLog { shots: Vec::new() })
+            .add_event::<Fire>()
+            .add_event::<Recall>()
+            .add_event::<Paradox>()
+            .add_systems(Update, repeat_history);
+        let mut ghost = app.world.spawn((
+                component::Ghost,
+                component::Unit { owner: 0 },
+                Sprite::default(),
+                Transform::default(),
+                component::Facing { value: 0.0 },
+                component::History { snapshots: attack_runs() },
+                TextureAtlas { layout: Handle::default(), index: 0 },
+                component::CurrentState { value: super::super::State::Idle },
+                component::CurrentAction { value: Action::None },
+                component::Health { current: UNIT_HEALTH, max: UNIT_HEALTH },
+                ));
+        if reversing {
+            ghost.insert(component::Reverse { elapsed: 0.0, rate: 1.0, paused: false });
+        } else {
+            ghost.insert(component::Repeat { elapsed: 0.0, rate: 1.0, paused: false });
+        }
+        if let Some(repeat_loop) = opt_loop {
+            ghost.insert(repeat_loop);
+        }
+        app.world.resource_mut::<Time>().advance_by(Duration::from_secs(seconds));
+        app.update();
+
+        let fired = app.world.resource::<Events<Fire>>().iter_current_update_events().map(|fire| fire.2).collect();
+        let recalled = app.world.resource_mut::<Events<Recall>>().drain().collect();
+        (fired, recalled)
+    }
+
+    fn muzzle_at(tick: usize) -> Vec2 {
+        muzzle(&attack_runs()[tick]).0
+    }
+
+    #[test]
+    fn repeat_fires_once_per_attack_run() {
+        let (fired, recalled) = play(ReverseFire::Recall, false, None, 2);
+        assert_eq!(fired, vec![muzzle_at(3), muzzle_at(6)]);
+        assert!(recalled.is_empty());
+    }
+
+    #[test]
+    fn loop_fires_every_pass_and_stops_after_its_count() {
+        // Five seconds of a one second recording, played twice.
+        let (fired, _) = play(ReverseFire::Recall, false, Some(component::Loop { remaining: Some(2) }), 5);
+        assert_eq!(fired, vec![muzzle_at(3), muzzle_at(6), muzzle_at(3), muzzle_at(6)]);
+    }
+
+    #[test]
+    fn reverse_recalls_once_per_attack_run() {
+        let (fired, recalled) = play(ReverseFire::Recall, true, None, 2);
+        assert!(fired.is_empty());
+        let muzzles: Vec<Vec2> = recalled.iter().map(|recall| recall.1).collect();
+        assert_eq!(muzzles, vec![muzzle_at(3), muzzle_at(6)]);
+        assert!(recalled.iter().all(|recall| recall.0 == 0 && recall.3.is_none()));
+    }
+
+    #[test]
+    fn history_stays_within_budget() {
+        let budget = HistoryBudget { bytes: crate::unit::HISTORY_BUDGET };
+        let mut store = SnapshotStore::default();
+        // Ten budgets' worth of ticks zigzagging, so none are folded together.
+        for tick in 0..budget.snapshots() * 10 {
+            record(&mut store, budget.snapshots(), Snapshot {
+                atlas_index: 0,
+                action: Action::None,
+                state: super::super::State::Move,
+                timestamp: tick as f32,
+                position: Vec3::new(tick as f32, (tick % 2) as f32 * 10.0, 0.0),
+                facing: 0.0,
+                direction: 0.0,
+                health: UNIT_HEALTH,
+                dead: false,
+            });
+            assert!(store.len() <= budget.snapshots());
+            // Sealed chunks are only dropped once fully expired, so up to one chunk of old
+            // snapshots and the open tail's allocation come on top of the budget.
+            let bytes = store.chunks().count() * crate::unit::store::chunk_bytes() + store.open_bytes();
+            assert!(bytes <= budget.bytes + 2 * crate::unit::store::chunk_bytes(), "{} bytes held at tick {}", bytes, tick);
+        }
+        assert_eq!(store.len(), budget.snapshots());
+        assert_eq!(store.back().map(|snapshot| snapshot.timestamp), Some((budget.snapshots() * 10 - 1) as f32));
+    }
+
+    fn moving(tick: usize, position: Vec3, facing: f32) -> Snapshot {
+        Snapshot {
+            atlas_index: 0,
+            action: Action::None,
+            state: super::super::State::Move,
+            timestamp: tick as f32 / 64.0,
+            position,
+            facing,
+            direction: facing - PI / 2.0,
+            health: UNIT_HEALTH,
+            dead: false,
+        }
+    }
+
+    #[test]
+    fn straight_line_keeps_two_keyframes() {
+        let mut store = SnapshotStore::default();
+        for tick in 0..200 {
+            record(&mut store, 1000, moving(tick, Vec3::new(tick as f32 * 1.5, tick as f32 * -0.5, 0.0), 0.3));
+        }
+        assert_eq!(store.len(), 2);
+        let halfway = sample(&store, 100.0 / 64.0).unwrap();
+        assert!(halfway.position.distance(Vec3::new(150.0, -50.0, 0.0)) < 0.01);
+    }
+
+    #[test]
+    fn turning_unit_plays_back_within_tolerance() {
+        let mut store = SnapshotStore::default();
+        let mut recorded = Vec::new();
+        // Walks a quarter circle, then straight on.
+        for tick in 0..300 {
+            let angle = (tick.min(200) as f32 / 200.0) * PI / 2.0;
+            let position = if tick <= 200 {
+                Vec3::new(angle.sin() * 200.0, 200.0 - angle.cos() * 200.0, 0.0)
+            } else {
+                Vec3::new(200.0 - (tick - 200) as f32, 200.0, 0.0)
+            };
+            let facing = angle + if tick <= 200 { 0.0 } else { PI / 2.0 };
+            let snapshot = moving(tick, position, facing);
+            recorded.push(snapshot.clone());
+            record(&mut store, 1000, snapshot);
+        }
+        assert!(store.len() < recorded.len() / 4, "{} keyframes", store.len());
+        for snapshot in recorded.iter() {
+            let played = sample(&store, snapshot.timestamp).unwrap();
+            assert!((played.position - snapshot.position).abs().max_element() <= POSITION_TOLERANCE + 0.001, "{:?} at {}", played.position, snapshot.timestamp);
+            assert!(angle_difference(played.facing, snapshot.facing).abs() <= ANGLE_TOLERANCE + 0.0001);
+        }
+    }
+
+    #[test]
+    fn reverse_ignores_shots_when_recall_is_off() {
+        let (fired, recalled) = play(ReverseFire::Ignore, true, None, 2);
+        assert!(fired.is_empty());
+        assert!(recalled.is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+use bevy::prelude::*;
+use super::{component::{Ancestor, Lineage, LineageUi}, GhostGenerations};
+
+const LINEAGE_FONT_SIZE: f32 = 16.0;
+const LINEAGE_COLOR: Color = Color::rgba(0.7, 0.7, 1.0, 0.8);
+
+/// Lineage of a new echo of `source`, or None if it would go past the generation limit.
+pub fn descend(
+    generations: &GhostGenerations,
+    source: Entity,
+    opt_lineage: Option<&Lineage>,
+    attempt: u32,
+    ) -> Option<Lineage> {
+    let mut ancestors = opt_lineage.map_or(Vec::new(), |lineage| lineage.ancestors.clone());
+    ancestors.push(Ancestor { entity: source, attempt });
+    let lineage = Lineage { ancestors };
+    if lineage.generation() > generations.max_depth {
+        warn!("Can't echo {:?}, generation {} is past the limit of {}", source, lineage.generation(), generations.max_depth);
+        return None;
+    }
+    info!("Echo generation {} costs {}: {}", lineage.generation(), generations.cost(lineage.generation()), lineage.describe());
+    Some(lineage)
+}
+
+pub fn spawn_lineage_ui(
+    commands: &mut Commands,
+    parent: Entity,
+    lineage: &Lineage,
+    ) {
+    let label = commands.spawn((
+            Text2dBundle {
+                text: Text::from_section(format!("G{}", lineage.generation()), TextStyle {
+                    font_size: LINEAGE_FONT_SIZE,
+                    color: LINEAGE_COLOR,
+                    ..default()
+                }),
+                transform: Transform::from_xyz(0.0, 65.0, 100.0),
+                ..default()
+            },
+            LineageUi,
+            )).id();
+
+    commands.entity(parent).add_child(label);
+}
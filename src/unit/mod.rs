@@ -1,26 +1,43 @@
-use std::{collections::VecDeque, f32::consts::PI};
-use bevy::{prelude::*, sprite::{MaterialMesh2dBundle, Mesh2dHandle}};
+use std::{collections::BTreeMap, f32::consts::PI};
+use archetype::UnitSpawner;
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
 use crate::input::component::{Selectable, Selected};
 use crate::AppState;
-use health::{HEALTH_BAR_BORDER, HEALTH_BAR_HEIGHT, HEALTH_BAR_WIDTH};
 
 pub mod action;
 mod animation;
+mod archetype;
 pub mod component;
-mod collision;
-mod health;
-mod history;
+pub(crate) mod collision;
+mod command_log;
+mod energy;
+pub(crate) mod health;
+pub(crate) mod history;
+mod lineage;
 mod movement;
+mod recording;
+mod steering;
+pub(crate) mod store;
+mod timeline;
 
-pub const UNIT_RADIUS: f32 = 20.0;
-pub const UNIT_MOVE_SPEED: f32 = 200.0;
-pub const UNIT_TURN_RATE: f32 = 10.0;
-pub const UNIT_ATTACK_RANGE: f32 = 500.0;
-pub const UNIT_ATTACK_TIMER: f32 = 1.0;
 pub const UNIT_HEALTH: i32 = 100;
-pub const UNIT_ANIMATION_TIMER: f32 = 0.08;
+pub const PLAYER_ARCHETYPE: &str = "marine";
+pub const ENEMY_ARCHETYPE: &str = "marine";
+pub const HISTORY_BUDGET: usize = 64 * 1024;
+pub const CHRONO_ENERGY_MAX: f32 = 100.0;
+pub const CHRONO_ENERGY_REGEN: f32 = 4.0;
+pub const CHRONO_ENERGY_PER_SECOND: f32 = 3.0;
+pub const GHOST_GENERATION_LIMIT: u32 = 3;
+pub const GHOST_GENERATION_COST: f32 = 1.0;
+pub const GHOST_GENERATION_GROWTH: f32 = 2.0;
+pub const GHOST_COLOR: Color = Color::rgba(0.5, 0.5, 1.0, 0.3);
+pub const ENEMY_COLOR: Color = Color::RED;
+pub const DEFAULT_COLOR: Color = Color::WHITE;
+/// How far a ghost or echo may get from where its recording had it before it is a paradox.
+pub const PARADOX_DISTANCE: f32 = 100.0;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum State {
     Idle,
     Move,
@@ -29,123 +46,215 @@ pub enum State {
     Dead,
     Stop,
     Halt,
+    Patrol,
+}
+
+/// Waves still to be spawned this round, held back until their archetypes have loaded.
+#[derive(Resource)]
+pub struct PendingWaves {
+    pub player: bool,
+    pub enemy: bool,
+}
+
+#[derive(Resource, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HistoryMode {
+    Snapshot,
+    Command,
+}
+
+/// Memory each unit may spend on its snapshot history.
+#[derive(Resource)]
+pub struct HistoryBudget {
+    pub bytes: usize,
+}
+
+impl HistoryBudget {
+    pub fn snapshots(&self) -> usize {
+        (self.bytes / std::mem::size_of::<history::Snapshot>()).max(2)
+    }
+}
+
+/// How deep echoes of echoes may go and what each generation costs.
+#[derive(Resource)]
+pub struct GhostGenerations {
+    pub max_depth: u32,
+    pub base_cost: f32,
+    pub growth: f32,
+}
+
+impl GhostGenerations {
+    pub fn cost(&self, generation: u32) -> f32 {
+        self.base_cost * self.growth.powi(generation.saturating_sub(1) as i32)
+    }
+}
+
+/// Energy each player spends on repeating, reversing and cloning, keyed by `Unit::owner`.
+/// Pools start full and regenerate by `regen` per second of game time.
+#[derive(Resource)]
+pub struct ChronoEnergy {
+    pub pools: HashMap<usize, f32>,
+    pub max: f32,
+    pub regen: f32,
+    pub per_second: f32,
+}
+
+impl ChronoEnergy {
+    pub fn available(&self, owner: usize) -> f32 {
+        self.pools.get(&owner).copied().unwrap_or(self.max)
+    }
+
+    /// Cost of manipulating `seconds` of history, scaled by the generation cost for clones.
+    pub fn cost(&self, seconds: f32, generation_cost: f32) -> f32 {
+        seconds * self.per_second * generation_cost
+    }
+
+    /// Takes `cost` from the owner's pool, leaving it untouched if there isn't enough.
+    pub fn spend(&mut self, owner: usize, cost: f32) -> bool {
+        let available = self.available(owner);
+        if available < cost {
+            return false;
+        }
+        self.pools.insert(owner, available - cost);
+        true
+    }
+}
+
+/// A time command refused for lack of energy: owner and the cost that could not be paid.
+#[derive(Event)]
+pub struct Refused(pub usize, pub f32);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ParadoxKind {
+    Death,
+    Divergence(f32),
+}
+
+#[derive(Event)]
+pub struct Paradox(pub Entity, pub ParadoxKind);
+
+#[derive(Resource, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParadoxRule {
+    Ignore,
+    Collapse,
+    FailRound,
+}
+
+#[derive(Resource, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReverseFire {
+    Recall,
+    Ignore,
+}
+
+/// Every finished attempt, keyed by the `Round` attempt number it was played in.
+#[derive(Resource)]
+pub struct Timelines {
+    pub branches: BTreeMap<u32, Branch>,
+    /// Branch the next attempt takes its ghosts from, the latest one when unset.
+    pub source: Option<u32>,
+    /// Branch the attempt in progress was forked from.
+    pub parent: Option<u32>,
+}
+
+pub struct Branch {
+    pub name: String,
+    pub parent: Option<u32>,
+    pub histories: Vec<recording::RecordedHistory>,
 }
 
 pub struct UnitPlugin;
 
 impl Plugin for UnitPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(OnEnter(AppState::InGame), (spawn, spawn_enemy))
-            .add_systems(OnEnter(AppState::RoundEnd), history::round_end)
-            .add_systems(OnEnter(AppState::RoundStart), history::round_repeat)
+        app.insert_resource(HistoryMode::Snapshot)
+            .insert_resource(HistoryBudget { bytes: HISTORY_BUDGET })
+            .insert_resource(ParadoxRule::Collapse)
+            .insert_resource(ReverseFire::Recall)
+            .insert_resource(GhostGenerations { max_depth: GHOST_GENERATION_LIMIT, base_cost: GHOST_GENERATION_COST, growth: GHOST_GENERATION_GROWTH })
+            .insert_resource(Timelines { branches: BTreeMap::new(), source: None, parent: None })
+            .insert_resource(ChronoEnergy { pools: HashMap::new(), max: CHRONO_ENERGY_MAX, regen: CHRONO_ENERGY_REGEN, per_second: CHRONO_ENERGY_PER_SECOND })
+            .init_asset::<archetype::Archetype>()
+            .init_asset_loader::<archetype::ArchetypeLoader>()
+            .add_systems(Startup, archetype::load_archetypes)
+            .add_event::<Paradox>()
+            .add_event::<Refused>()
+            .insert_resource(PendingWaves { player: false, enemy: false })
+            .add_systems(OnEnter(AppState::InGame), queue_waves)
+            .add_systems(OnEnter(AppState::RoundEnd), (history::round_end, timeline::store_branch))
+            .add_systems(OnEnter(AppState::RoundStart), (timeline::fork.before(history::round_repeat), history::round_repeat))
             .add_systems(Update, (
                     show_selection,
                     health::health_ui,
                     animation::animate_texture_atlas,
                     action::read_action,
-                    action::engage,
-                    history::start_repeat,
-                    history::start_reverse,
-                    movement::arrive,
-                    movement::apply_velocity.after(collision::collision),
-                    movement::calculate_direct_velocity.after(movement::turn_towards_target),
+                    action::engage.after(collision::index_units),
+                    history::start_repeat.run_if(resource_equals(HistoryMode::Snapshot)),
+                    history::start_reverse.run_if(resource_equals(HistoryMode::Snapshot)),
+                    history::seek.run_if(in_state(AppState::InGame)),
+                    history::repeat_history.run_if(in_state(AppState::InGame)),
+                    history::resolve_paradox.run_if(in_state(AppState::InGame)),
+                    command_log::start_repeat.run_if(resource_equals(HistoryMode::Command)),
+                    command_log::track_commands.after(crate::input::mouse::act).run_if(in_state(AppState::InGame)).run_if(resource_equals(HistoryMode::Command)),
+                    command_log::repeat_commands.run_if(in_state(AppState::InGame)),
+                    movement::arrive.after(movement::plan_path),
+                    movement::apply_velocity.after(steering::steer),
+                    steering::steer.after(movement::turn_towards_target),
                     movement::turn_towards_target,
-                    collision::collision.after(movement::calculate_direct_velocity),
+                    collision::collision.after(movement::apply_velocity),
+                    ))
+            .add_systems(Update, (
+                    recording::export,
+                    recording::import,
+                    timeline::select_branch,
+                    timeline::compare_branch.run_if(in_state(AppState::InGame)),
+                    energy::regenerate.run_if(in_state(AppState::InGame)),
+                    archetype::index_archetypes,
+                    action::read_queue.before(action::advance_queue),
+                    action::advance_queue.before(action::read_action),
+                    action::draw_waypoints,
+                    movement::follow_patrol.after(action::read_action).before(movement::turn_towards_target),
+                    movement::plan_path.after(movement::follow_patrol).before(movement::turn_towards_target),
+                    movement::keep_formation.after(movement::plan_path).before(steering::steer),
+                    collision::obstacles.after(collision::collision),
+                    spawn.run_if(in_state(AppState::InGame)),
+                    spawn_enemy.run_if(in_state(AppState::InGame)),
+                    collision::index_units
+                        .after(collision::obstacles)
+                        .after(history::start_repeat)
+                        .after(history::start_reverse)
+                        .after(command_log::start_repeat)
+                        .after(history::repeat_history)
+                        .after(history::seek),
                     ))
             .add_systems(FixedUpdate, (
                     action::attack.run_if(in_state(AppState::InGame)),
                     health::health.after(action::attack).run_if(in_state(AppState::InGame)),
-                    history::track_history.run_if(in_state(AppState::InGame)),
-                    history::repeat_history.run_if(in_state(AppState::InGame)),
+                    history::track_history.run_if(in_state(AppState::InGame)).run_if(resource_equals(HistoryMode::Snapshot)),
                     ));
     }
 }
 
 
+pub fn queue_waves(
+    mut waves: ResMut<PendingWaves>,
+    ) {
+    waves.player = true;
+    waves.enemy = true;
+}
+
 pub fn spawn(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut spawner: UnitSpawner,
+    mut waves: ResMut<PendingWaves>,
     ) {
+    // The archetypes folder may still be loading when the round starts.
+    if !waves.player || !spawner.is_loaded(PLAYER_ARCHETYPE) {
+        return;
+    }
+    waves.player = false;
     for n in 0..10 {
-        let texture = asset_server.load::<Image>("marine.png");
-        let layout = TextureAtlasLayout::from_grid(Vec2::new(100.0, 100.0), 8, 11, None, None);
-        let texture_atlas_layout = texture_atlas_layouts.add(layout);
-        let spawn_transform = Transform::from_xyz((n * 30) as f32, (n * 30) as f32, 0.0);
-        let parent = commands.spawn(SpriteSheetBundle {
-            sprite: Sprite {
-                color: Color::WHITE,
-                ..default()
-            },
-            texture,
-            atlas: TextureAtlas {
-                layout: texture_atlas_layout,
-                index: 0,
-            },
-            transform: spawn_transform,
-            ..default()
-        }).id();
-
-        commands.entity(parent).insert((
-                Selectable,
-                component::Unit{ owner: 0 },
-                component::Radius { value: UNIT_RADIUS },
-                component::Velocity { x: 0.0, y: 0.0 },
-                component::MoveSpeed { value: UNIT_MOVE_SPEED },
-                component::Facing { value: (2.0 * PI / 10.0) * n as f32 },
-                component::TurnRate { value: UNIT_TURN_RATE },
-                component::Target { entity: None, x: 0.0, y: 0.0 },
-                component::CurrentAction { value: action::Action::None },
-                component::Attack { range: UNIT_ATTACK_RANGE, timer: Timer::from_seconds(UNIT_ATTACK_TIMER, TimerMode::Once) },
-                component::CurrentState { value: State::Idle },
-                component::History { snapshots: VecDeque::new() },
-                component::Health { current: UNIT_HEALTH, max: UNIT_HEALTH },
-                component::AnimationIndices { current: 0, first: 0, last: 7 },
-                component::AnimationTimer { timer: Timer::from_seconds(UNIT_ANIMATION_TIMER, TimerMode::Repeating) },
-                ));
-
-        let child_texture = asset_server.load::<Image>("selection_circle.png");
-        let child = commands.spawn((
-                SpriteBundle {
-                    sprite: Sprite {
-                        color: Color::WHITE,
-                        ..default()
-                    },
-                    texture: child_texture,
-                    transform: Transform::from_xyz(0.0, -25.0, -100.0),
-                    visibility: Visibility::Hidden,
-                    ..default()
-                },
-                component::SelectionCircleUi
-                )).id();
-
-        commands.entity(parent).add_child(child);
-
-        let outer_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH + HEALTH_BAR_BORDER, HEALTH_BAR_HEIGHT + HEALTH_BAR_BORDER)));
-        let inner_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)));
-        let outer_color = Color::BLACK;
-        let inner_color = Color::GREEN;
-
-        let outer = commands.spawn((MaterialMesh2dBundle {
-            mesh: outer_shape,
-            material: materials.add(outer_color),
-            transform: Transform::from_xyz(0.0, 50.0, 100.0),
-            ..default()
-        },
-        component::HealthBarUi)).id();
-
-        let inner = commands.spawn((MaterialMesh2dBundle {
-            mesh: inner_shape,
-            material: materials.add(inner_color),
-            transform: Transform::from_xyz(0.0, 50.0, 101.0),
-            ..default()
-        },
-        component::HealthBarAmountUi)).id();
-
-        commands.entity(parent).add_child(outer);
-        commands.entity(parent).add_child(inner);
+        let position = Vec3::new((n * 30) as f32, (n * 30) as f32, 0.0);
+        if let Some(parent) = spawner.spawn(PLAYER_ARCHETYPE, 0, position, (2.0 * PI / 10.0) * n as f32, Color::WHITE) {
+            spawner.commands.entity(parent).insert(Selectable);
+        }
     }
 }
 
@@ -167,72 +276,17 @@ pub fn show_selection(
 }
 
 pub fn spawn_enemy(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut spawner: UnitSpawner,
+    mut waves: ResMut<PendingWaves>,
     ) {
-    for n in 0..10 {
-        let texture = asset_server.load::<Image>("marine.png");
-        let layout = TextureAtlasLayout::from_grid(Vec2::new(100.0, 100.0), 8, 11, None, None);
-        let texture_atlas_layout = texture_atlas_layouts.add(layout);
-        let spawn_transform = Transform::from_xyz(-100.0, -100.0, 0.0);
-        let parent = commands.spawn(
-            SpriteSheetBundle {
-                sprite: Sprite {
-                    color: Color::RED,
-                    ..default()
-                },
-                texture,
-                atlas: TextureAtlas {
-                    layout: texture_atlas_layout,
-                    index: 0,
-                },
-                transform: spawn_transform,
-                ..default()
-            },
-            ).insert((
-                    component::Unit { owner: 1 },
-                    component::Enemy,
-                    component::Radius { value: UNIT_RADIUS },
-                    component::Velocity { x: 0.0, y: 0.0 },
-                    component::MoveSpeed { value: UNIT_MOVE_SPEED },
-                    component::TurnRate { value: UNIT_TURN_RATE },
-                    component::Facing { value: 0.0 },
-                    component::Target { entity: None, x: 0.0, y: 0.0 },
-                    component::CurrentAction { value: action::Action::None },
-                    component::Attack { range: UNIT_ATTACK_RANGE, timer: Timer::from_seconds(UNIT_ATTACK_TIMER, TimerMode::Once) },
-                    component::CurrentState { value: State::Idle },
-                    component::History { snapshots: VecDeque::new() },
-                    component::Health { current: UNIT_HEALTH, max: UNIT_HEALTH },
-                    component::AnimationIndices { current: 0, first: 0, last: 7 },
-                    component::AnimationTimer { timer: Timer::from_seconds(UNIT_ANIMATION_TIMER, TimerMode::Repeating) },
-                    )).id();
-
-
-        let outer_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH + HEALTH_BAR_BORDER, HEALTH_BAR_HEIGHT + HEALTH_BAR_BORDER)));
-        let inner_shape = Mesh2dHandle(meshes.add(Rectangle::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)));
-        let outer_color = Color::BLACK;
-        let inner_color = Color::GREEN;
-
-        let outer = commands.spawn((MaterialMesh2dBundle {
-            mesh: outer_shape,
-            material: materials.add(outer_color),
-            transform: Transform::from_xyz(0.0, 50.0, 100.0),
-            ..default()
-        },
-        component::HealthBarUi)).id();
-
-        let inner = commands.spawn((MaterialMesh2dBundle {
-            mesh: inner_shape,
-            material: materials.add(inner_color),
-            transform: Transform::from_xyz(0.0, 50.0, 101.0),
-            ..default()
-        },
-        component::HealthBarAmountUi)).id();
-
-        commands.entity(parent).add_child(outer);
-        commands.entity(parent).add_child(inner);
+    if !waves.enemy || !spawner.is_loaded(ENEMY_ARCHETYPE) {
+        return;
+    }
+    waves.enemy = false;
+    for _ in 0..10 {
+        let position = Vec3::new(-100.0, -100.0, 0.0);
+        if let Some(parent) = spawner.spawn(ENEMY_ARCHETYPE, 1, position, 0.0, Color::RED) {
+            spawner.commands.entity(parent).insert(component::Enemy);
+        }
     }
 }
@@ -1,49 +1,196 @@
 use bevy::prelude::*;
 
+use bevy::utils::HashMap;
+use crate::map::{flow_field::FlowField, pathfinding::find_path, FlowFields, NavGrid};
 use super::{component, component::AsVec2, State};
 
 const ARRIVAL_DISTANCE: f32 = 5.0;
+pub const WAYPOINT_DISTANCE: f32 = 25.0;
+/// Units sent to one point together from this many on share a flow field.
+const FLOW_GROUP_SIZE: usize = 10;
+/// Slack on the arrival disc of a group, as circles never pack its area completely.
+const CLUSTER_SPREAD: f32 = 1.5;
+/// How far ahead of the rest of its formation a unit may get before it has slowed to
+/// `MIN_FORMATION_PACE` of the group's speed.
+const FORMATION_SLACK: f32 = 100.0;
+const MIN_FORMATION_PACE: f32 = 0.3;
 
+fn is_moving(state: State) -> bool {
+    state == State::Move || state == State::AttackMove || state == State::Patrol
+}
+
+/// The formation a moving unit is still walking in, if any.
+pub fn formation_slot<'a>(state: State, target: &component::Target, opt_slot: Option<&'a component::FormationSlot>) -> Option<&'a component::FormationSlot> {
+    opt_slot.filter(|slot| is_moving(state) && slot.position == target.as_vec2())
+}
+
+/// Moves units by the velocity steering left them with, which carries over between frames.
 pub fn apply_velocity(
     time: Res<Time>,
-    mut query: Query<(&mut Transform, &mut component::Velocity), (With<component::Unit>, Without<component::Ghost>)>,
+    mut query: Query<(&mut Transform, &mut component::Velocity, Has<component::Dead>), (With<component::Unit>, Without<component::Ghost>)>,
     ) {
-    for (mut transform, mut velocity) in query.iter_mut() {
+    for (mut transform, mut velocity, dead) in query.iter_mut() {
+        if dead {
+            velocity.x = 0.0;
+            velocity.y = 0.0;
+            continue;
+        }
         transform.translation.x += velocity.x * time.delta_seconds();
         transform.translation.y += velocity.y * time.delta_seconds();
         transform.translation.z = -transform.translation.y;
-        velocity.x = 0.0;
-        velocity.y = 0.0;
     }
 }
 
-pub fn calculate_direct_velocity(
-    mut query: Query<(&mut component::Velocity, &component::MoveSpeed, &component::Facing, &component::CurrentState), (With<component::Unit>, Without<component::Ghost>)>,
+pub fn arrive(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut component::CurrentState, &component::Target, &component::Path, &Transform, Option<&mut component::Patrol>), (With<component::Unit>, Without<component::Ghost>)>,
     ) {
-    for (mut velocity, move_speed, facing, state) in query.iter_mut() {
-        if state.value != State::Move {
+    for (entity, mut state, target, path, transform, opt_patrol) in query.iter_mut() {
+        if !is_moving(state.value) {
             continue;
         }
-        let direction = Vec2::new(facing.value.cos(), facing.value.sin()).normalize();
-        velocity.x = direction.x * move_speed.value;
-        velocity.y = direction.y * move_speed.value;
+        // A target inside a wall is reached at the closest point the path could get to.
+        let goal = path.waypoints.back().copied().unwrap_or(target.as_vec2());
+        let distance = transform.translation.xy().distance(goal);
+        if distance < path.arrival {
+            if state.value == State::Patrol {
+                if let Some(mut patrol) = opt_patrol {
+                    patrol.next = (patrol.next + 1) % patrol.points.len();
+                    continue;
+                }
+            }
+            state.value = State::Idle;
+            commands.entity(entity).remove::<component::Destination>();
+            commands.entity(entity).remove::<component::FormationSlot>();
+        }
     }
 }
 
-pub fn arrive(
-    mut query: Query<(&mut component::Velocity, &mut component::CurrentState, &component::Target, &Transform), (With<component::Unit>, Without<component::Ghost>)>,
+/// Points a patrolling unit at the next point of its route, also after it broke off to fight.
+pub fn follow_patrol(
+    mut query: Query<(&mut component::Target, &component::CurrentState, &component::Patrol), (With<component::Unit>, Without<component::Ghost>)>,
     ) {
-    for (mut velocity, mut state, target, transform) in query.iter_mut() {
-        if state.value != State::Move {
+    for (mut target, state, patrol) in query.iter_mut() {
+        if state.value != State::Patrol {
             continue;
         }
-        let distance = transform.translation.xy().distance(target.as_vec2());
-        if distance < ARRIVAL_DISTANCE {
-            state.value = State::Idle;
-            velocity.x = 0.0;
-            velocity.y = 0.0;
+        if let Some(point) = patrol.points.get(patrol.next) {
+            target.x = point.x;
+            target.y = point.y;
+        }
+    }
+}
+
+/// Plans a path whenever a moving unit's target changes and drops waypoints as they are passed.
+/// Units given the same target in the same frame are a group order; large ones share a flow
+/// field and settle in a cluster that grows with the group instead of all reaching for one point.
+/// A formation shares the flow field of its anchor whatever its size, each unit leaving it for
+/// its own slot once that is in sight.
+pub fn plan_path(
+    grid: Res<NavGrid>,
+    mut flow_fields: ResMut<FlowFields>,
+    mut query: Query<(&Transform, &component::Target, &component::CurrentState, &component::Radius, &mut component::Path, Option<&component::FormationSlot>), (With<component::Unit>, Without<component::Ghost>)>,
+    ) {
+    let mut groups: HashMap<(u32, u32), usize> = HashMap::new();
+    for (_, target, state, _, path, _) in query.iter() {
+        if is_moving(state.value) && path.goal != Some(target.as_vec2()) {
+            *groups.entry(FlowFields::key(target.as_vec2())).or_default() += 1;
+        }
+    }
+    for (transform, target, state, radius, mut path, opt_slot) in query.iter_mut() {
+        if !is_moving(state.value) {
+            if let Some(_) = path.goal {
+                path.goal = None;
+                path.flow = None;
+                path.waypoints.clear();
+            }
+            continue;
+        }
+        let position = transform.translation.xy();
+        let formation = formation_slot(state.value, target, opt_slot);
+        if path.goal != Some(target.as_vec2()) {
+            path.goal = Some(target.as_vec2());
+            path.flow = None;
+            path.arrival = ARRIVAL_DISTANCE;
+            let key = FlowFields::key(target.as_vec2());
+            let group = groups.get(&key).copied().unwrap_or(1);
+            if let Some(slot) = formation {
+                let anchor_key = FlowFields::key(slot.anchor);
+                if !flow_fields.fields.contains_key(&anchor_key) {
+                    if let Some(field) = FlowField::new(&grid, slot.anchor) {
+                        flow_fields.fields.insert(anchor_key, field);
+                    }
+                }
+                if flow_fields.fields.contains_key(&anchor_key) {
+                    path.flow = Some(anchor_key);
+                }
+            } else if group >= FLOW_GROUP_SIZE {
+                if !flow_fields.fields.contains_key(&key) {
+                    if let Some(field) = FlowField::new(&grid, target.as_vec2()) {
+                        flow_fields.fields.insert(key, field);
+                    }
+                }
+                if flow_fields.fields.contains_key(&key) {
+                    path.flow = Some(key);
+                    // Roughly the radius of a disc the whole group fits in.
+                    path.arrival = radius.value * (group as f32).sqrt() * CLUSTER_SPREAD;
+                }
+            }
+            if let None = path.flow {
+                let waypoints = find_path(&grid, position, target.as_vec2()).unwrap_or_else(|| vec![target.as_vec2()]);
+                path.waypoints = waypoints.into();
+            }
+        }
+        if let Some(key) = path.flow {
+            if let Some(field) = flow_fields.fields.get(&key) {
+                let mut waypoints = field.waypoints(&grid, position);
+                if let Some(_) = formation {
+                    // Off the shared field and into the slot once there is a clear line to it.
+                    if grid.line_of_sight(position, target.as_vec2()) {
+                        waypoints.clear();
+                    } else {
+                        waypoints.truncate(1);
+                    }
+                    waypoints.push(target.as_vec2());
+                }
+                path.waypoints = waypoints.into();
+            }
+            continue;
+        }
+        while path.waypoints.len() > 1 && path.waypoints.front().is_some_and(|waypoint| position.distance(*waypoint) < WAYPOINT_DISTANCE) {
+            path.waypoints.pop_front();
         }
     }
+    let in_use: Vec<(u32, u32)> = query.iter()
+        .filter_map(|(_, _, _, _, path, _)| path.flow)
+        .collect();
+    flow_fields.fields.retain(|key, _| in_use.contains(key));
+}
+
+/// Keeps each formation in step: the group walks at its slowest member's speed, and a unit
+/// further along towards its slot than the group on average slows down for the rest.
+pub fn keep_formation(
+    mut query: Query<(&Transform, &component::MoveSpeed, &component::CurrentState, &component::Target, &mut component::FormationSlot), (With<component::Unit>, Without<component::Ghost>, Without<component::Dead>)>,
+    ) {
+    // Per anchor: slowest speed, summed distance still to go and member count.
+    let mut groups: HashMap<(u32, u32), (f32, f32, usize)> = HashMap::new();
+    for (transform, move_speed, state, target, slot) in query.iter() {
+        if let None = formation_slot(state.value, target, Some(slot)) {
+            continue;
+        }
+        let group = groups.entry(FlowFields::key(slot.anchor)).or_insert((f32::MAX, 0.0, 0));
+        group.0 = group.0.min(move_speed.value);
+        group.1 += transform.translation.xy().distance(slot.position);
+        group.2 += 1;
+    }
+    for (transform, _, _, _, mut slot) in query.iter_mut() {
+        let Some((speed, remaining, members)) = groups.get(&FlowFields::key(slot.anchor)).copied() else {
+            continue;
+        };
+        let average = remaining / members as f32;
+        let ahead = average - transform.translation.xy().distance(slot.position);
+        slot.pace = speed * (1.0 - ahead / FORMATION_SLACK).clamp(MIN_FORMATION_PACE, 1.0);
+    }
 }
 
 pub fn rotate_facing(
@@ -53,7 +200,9 @@ pub fn rotate_facing(
     target_point: Vec3,
     ) -> Result<f32, String> {
     let forward = Vec2::new(facing.cos(), facing.sin()).normalize();
-    let to_target = (target_point - current_point).truncate().normalize();
+    let Some(to_target) = (target_point - current_point).truncate().try_normalize() else {
+        return Err("already at target".to_string());
+    };
     let forward_dot_target = forward.dot(to_target);
 
     if (forward_dot_target - 1.0).abs() < f32::EPSILON {
@@ -70,11 +219,17 @@ pub fn rotate_facing(
 
 pub fn turn_towards_target(
     time: Res<Time>,
-    mut query: Query<(&Transform, &component::TurnRate, &mut component::Facing, &component::Target, &component::CurrentState), (With<component::Unit>, Without<component::Ghost>)>,
+    mut query: Query<(&Transform, &component::TurnRate, &mut component::Facing, &component::Target, &component::Path, &component::CurrentState), (With<component::Unit>, Without<component::Ghost>)>,
     ) {
-    for (transform, turn_rate, mut facing, target, state) in query.iter_mut() {
+    for (transform, turn_rate, mut facing, target, path, state) in query.iter_mut() {
         let turn_amount = turn_rate.value * time.delta_seconds();
-        if let Ok(face) = rotate_facing(transform.translation, facing.value, turn_amount, target.as_vec2().extend(0.0)) {
+        let mut aim = target.as_vec2();
+        if is_moving(state.value) {
+            if let Some(waypoint) = path.waypoints.front() {
+                aim = *waypoint;
+            }
+        }
+        if let Ok(face) = rotate_facing(transform.translation, facing.value, turn_amount, aim.extend(0.0)) {
             facing.value -= face;
         }
     }
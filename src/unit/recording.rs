@@ -0,0 +1,255 @@
+use std::{fmt, fs, io, path::{Path, PathBuf}};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::game::Round;
+use crate::input::{component::Selectable, Export, Import};
+use super::{component, archetype::UnitSpawner, GHOST_COLOR, PLAYER_ARCHETYPE};
+
+pub const RECORDING_VERSION: u32 = 3;
+/// Version 1 predates health in snapshots and version 2 predates archetypes, those fields
+/// fall back to their defaults.
+const SUPPORTED_VERSIONS: [u32; 3] = [1, 2, RECORDING_VERSION];
+const RECORDING_DIRECTORY: &str = "recordings";
+
+#[derive(Serialize, Deserialize)]
+pub struct Recording {
+    pub version: u32,
+    pub round: u32,
+    pub histories: Vec<RecordedHistory>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecordedHistory {
+    pub owner: usize,
+    pub enemy: bool,
+    #[serde(default = "default_archetype")]
+    pub archetype: String,
+    pub history: component::History,
+}
+
+fn default_archetype() -> String {
+    PLAYER_ARCHETYPE.to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Recording")]
+struct RecordingHeader {
+    version: u32,
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    Format(String),
+    UnsupportedVersion(u32),
+    NotFound,
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordingError::Io(error) => write!(f, "could not access recording: {}", error),
+            RecordingError::Format(error) => write!(f, "recording is malformed: {}", error),
+            RecordingError::UnsupportedVersion(version) => {
+                let supported: Vec<String> = SUPPORTED_VERSIONS.iter().map(|version| version.to_string()).collect();
+                write!(f, "recording version {} is not supported (supported versions: {})", version, supported.join(", "))
+            }
+            RecordingError::NotFound => write!(f, "no recordings found in '{}'", RECORDING_DIRECTORY),
+        }
+    }
+}
+
+impl From<io::Error> for RecordingError {
+    fn from(error: io::Error) -> Self {
+        RecordingError::Io(error)
+    }
+}
+
+pub fn save(recording: &Recording, path: &Path) -> Result<(), RecordingError> {
+    let text = ron::ser::to_string_pretty(recording, ron::ser::PrettyConfig::default())
+        .map_err(|error| RecordingError::Format(error.to_string()))?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(path, text)?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<Recording, RecordingError> {
+    let text = fs::read_to_string(path)?;
+    let header: RecordingHeader = ron::from_str(&text)
+        .map_err(|error| RecordingError::Format(error.to_string()))?;
+    if !SUPPORTED_VERSIONS.contains(&header.version) {
+        return Err(RecordingError::UnsupportedVersion(header.version));
+    }
+    ron::from_str(&text).map_err(|error| RecordingError::Format(error.to_string()))
+}
+
+fn latest_recording() -> Result<PathBuf, RecordingError> {
+    let mut latest: Option<(std::time::SystemTime, PathBuf)> = None;
+    for entry in fs::read_dir(RECORDING_DIRECTORY)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "ron") {
+            continue;
+        }
+        let modified = fs::metadata(&path)?.modified()?;
+        if latest.as_ref().is_none_or(|(newest, _)| modified > *newest) {
+            latest = Some((modified, path));
+        }
+    }
+    latest.map(|(_, path)| path).ok_or(RecordingError::NotFound)
+}
+
+pub fn export(
+    mut export_reader: EventReader<Export>,
+    query: Query<(&component::Unit, &component::Kind, &component::History, Option<&component::Enemy>)>,
+    round: Res<Round>,
+    ) {
+    for _ in export_reader.read() {
+        let mut histories = Vec::new();
+        for (unit, kind, history, opt_enemy) in query.iter() {
+            if history.snapshots.is_empty() {
+                continue;
+            }
+            histories.push(RecordedHistory {
+                owner: unit.owner,
+                enemy: opt_enemy.is_some(),
+                archetype: kind.name.clone(),
+                history: history.clone(),
+            });
+        }
+        let recording = Recording {
+            version: RECORDING_VERSION,
+            round: round.attempts,
+            histories,
+        };
+        let path = Path::new(RECORDING_DIRECTORY).join(format!("attempt_{:03}.ron", round.attempts));
+        match save(&recording, &path) {
+            Ok(()) => info!("Exported {} histories to {}", recording.histories.len(), path.display()),
+            Err(error) => error!("Export failed: {}", error),
+        }
+    }
+}
+
+pub fn import(
+    mut import_reader: EventReader<Import>,
+    mut spawner: UnitSpawner,
+    ) {
+    for _ in import_reader.read() {
+        let recording = match latest_recording().and_then(|path| load(&path)) {
+            Ok(recording) => recording,
+            Err(error) => {
+                error!("Import failed: {}", error);
+                continue;
+            }
+        };
+        info!("Importing {} histories from round {}", recording.histories.len(), recording.round);
+        for recorded in recording.histories {
+            spawn_ghost(&mut spawner, recorded);
+        }
+    }
+}
+
+/// Spawns a ghost replaying a recorded history from its first snapshot on.
+pub fn spawn_ghost(
+    spawner: &mut UnitSpawner,
+    recorded: RecordedHistory,
+    ) {
+    let history = recorded.history;
+    let Some(first_snapshot) = history.snapshots.front().cloned() else {
+        return;
+    };
+    let Some(last_snapshot) = history.snapshots.back().cloned() else {
+        return;
+    };
+    let Some(parent) = spawner.spawn(&recorded.archetype, recorded.owner, first_snapshot.position, first_snapshot.facing, GHOST_COLOR) else {
+        return;
+    };
+    spawner.commands.entity(parent).insert((
+            component::Ghost,
+            component::Target { entity: None, x: last_snapshot.position.x, y: last_snapshot.position.y },
+            history,
+            component::Repeat { elapsed: 0.0, rate: 1.0, paused: false },
+            component::Drift::default(),
+            ));
+
+    if recorded.enemy {
+        spawner.commands.entity(parent).insert(component::Enemy);
+    } else {
+        spawner.commands.entity(parent).insert(Selectable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{action::Action, history::Snapshot, State, UNIT_HEALTH};
+
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("time_stabs_{}_{}.ron", std::process::id(), name))
+    }
+
+    fn recording(version: u32) -> Recording {
+        let snapshots: Vec<Snapshot> = (0..3).map(|tick| Snapshot {
+            atlas_index: tick,
+            action: Action::None,
+            state: State::Move,
+            timestamp: tick as f32 * 0.5,
+            position: Vec3::new(tick as f32 * 10.0, -5.0, 0.0),
+            facing: 1.0,
+            direction: 0.0,
+            health: UNIT_HEALTH - tick as i32,
+            dead: false,
+        }).collect();
+        Recording {
+            version,
+            round: 2,
+            histories: vec![RecordedHistory {
+                owner: 1,
+                enemy: true,
+                archetype: PLAYER_ARCHETYPE.to_string(),
+                history: component::History { snapshots: snapshots.into() },
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_histories() {
+        let path = scratch_path("round_trip");
+        save(&recording(RECORDING_VERSION), &path).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.version, RECORDING_VERSION);
+        assert_eq!(loaded.round, 2);
+        assert_eq!(loaded.histories.len(), 1);
+        let recorded = &loaded.histories[0];
+        assert_eq!((recorded.owner, recorded.enemy, recorded.archetype.as_str()), (1, true, PLAYER_ARCHETYPE));
+        let snapshots = &recorded.history.snapshots;
+        assert_eq!(snapshots.len(), 3);
+        for tick in 0..3 {
+            assert_eq!(snapshots[tick].timestamp, tick as f32 * 0.5);
+            assert_eq!(snapshots[tick].position, Vec3::new(tick as f32 * 10.0, -5.0, 0.0));
+            assert_eq!(snapshots[tick].health, UNIT_HEALTH - tick as i32);
+            assert_eq!(snapshots[tick].atlas_index, tick);
+        }
+    }
+
+    #[test]
+    fn unsupported_versions_are_refused() {
+        for version in [0, RECORDING_VERSION + 1] {
+            let path = scratch_path(&format!("version_{}", version));
+            save(&recording(version), &path).unwrap();
+            let result = load(&path);
+            fs::remove_file(&path).unwrap();
+
+            match result {
+                Err(error @ RecordingError::UnsupportedVersion(found)) => {
+                    assert_eq!(found, version);
+                    assert!(error.to_string().contains("supported versions: 1, 2, 3"));
+                }
+                _ => panic!("version {} should have been refused", version),
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use bevy::prelude::*;
+
+use crate::map::{spatial::SpatialGrid, Obstacles};
+use super::{component, component::AsVec2, movement, State};
+
+/// Distance from the goal at which an arriving unit starts to brake.
+const SLOWING_DISTANCE: f32 = 100.0;
+/// Slowest an arriving unit walks, so it still reaches the goal instead of creeping up on it.
+const MIN_ARRIVAL_SPEED: f32 = 20.0;
+/// Velocity change per second, as a multiple of the unit's move speed. High enough that a unit
+/// at full speed turns tighter than `movement::WAYPOINT_DISTANCE` and never circles a waypoint.
+const ACCELERATION: f32 = 10.0;
+/// Units keep this many radii apart before separation lets go of them.
+const SEPARATION_RANGE: f32 = 2.5;
+const SEPARATION_WEIGHT: f32 = 0.6;
+/// How many seconds of movement a unit looks ahead for walls.
+const AVOIDANCE_LOOK_AHEAD: f32 = 0.3;
+const AVOIDANCE_WEIGHT: f32 = 1.0;
+
+/// Full speed straight at `target`.
+pub fn seek(position: Vec2, target: Vec2, max_speed: f32) -> Vec2 {
+    (target - position).normalize_or_zero() * max_speed
+}
+
+/// Seek that brakes over `SLOWING_DISTANCE` once within it of stopping `stop_distance` short of
+/// the target.
+pub fn arrive(position: Vec2, target: Vec2, max_speed: f32, stop_distance: f32) -> Vec2 {
+    let remaining = position.distance(target) - stop_distance;
+    let speed = (max_speed * remaining / SLOWING_DISTANCE).clamp(MIN_ARRIVAL_SPEED.min(max_speed), max_speed);
+    seek(position, target, speed)
+}
+
+/// Pushes away from every neighbour closer than `range`, harder the closer it is.
+pub fn separation(position: Vec2, neighbours: impl Iterator<Item = Vec2>, range: f32, max_speed: f32) -> Vec2 {
+    let mut push = Vec2::ZERO;
+    for neighbour in neighbours {
+        let offset = position - neighbour;
+        let distance = offset.length();
+        if distance >= range || distance <= 0.0 {
+            continue;
+        }
+        push += offset / distance * (1.0 - distance / range);
+    }
+    push * max_speed
+}
+
+/// Steers sideways off a wall the unit would run into within the look-ahead, leaving the
+/// wall-free side of the velocity alone.
+pub fn avoid(position: Vec2, velocity: Vec2, radius: f32, obstacles: &Obstacles, max_speed: f32) -> Vec2 {
+    let ahead = position + velocity * AVOIDANCE_LOOK_AHEAD;
+    let mut steer = Vec2::ZERO;
+    for rect in obstacles.rects.iter() {
+        let expanded = Rect::from_corners(rect.min - Vec2::splat(radius), rect.max + Vec2::splat(radius));
+        if !expanded.contains(ahead) {
+            continue;
+        }
+        let away = (ahead - rect.center()).normalize_or_zero();
+        // Only the part of `away` across the direction of travel, so units slide along walls.
+        let forward = velocity.normalize_or_zero();
+        let sideways = (away - forward * away.dot(forward)).normalize_or_zero();
+        steer += if sideways == Vec2::ZERO { away } else { sideways } * max_speed;
+    }
+    steer
+}
+
+/// Blends seek or arrive, separation and wall avoidance into the velocity each unit wants,
+/// then eases its `Velocity` towards it at a bounded acceleration.
+pub fn steer(
+    time: Res<Time>,
+    grid: Res<SpatialGrid>,
+    obstacles: Res<Obstacles>,
+    mut query: Query<(Entity, &Transform, &mut component::Velocity, &component::MoveSpeed, &component::Radius, &component::CurrentState, &component::Target, &component::Path, Has<component::HoldPosition>, Option<&component::FormationSlot>), (With<component::Unit>, Without<component::Ghost>, Without<component::Dead>)>,
+    solid_query: Query<(), (With<component::Unit>, Without<component::Ghost>, Without<component::Dead>)>,
+    ) {
+    for (entity, transform, mut velocity, move_speed, radius, state, target, path, holding, opt_slot) in query.iter_mut() {
+        let position = transform.translation.xy();
+        let mut max_speed = move_speed.value;
+        if let Some(slot) = movement::formation_slot(state.value, target, opt_slot) {
+            max_speed = max_speed.min(slot.pace);
+        }
+        let mut desired = Vec2::ZERO;
+        if state.value == State::Move || state.value == State::AttackMove || state.value == State::Patrol {
+            let aim = path.waypoints.front().copied().unwrap_or(target.as_vec2());
+            if path.waypoints.len() > 1 {
+                desired = seek(position, aim, max_speed);
+            } else {
+                desired = arrive(position, aim, max_speed, path.arrival);
+            }
+        }
+        // A unit holding position stays put, everyone else gives way around it.
+        if !holding {
+            let neighbours = grid.nearby(position, radius.value * SEPARATION_RANGE)
+                .filter(|(other, _)| *other != entity && solid_query.contains(*other))
+                .map(|(_, neighbour)| neighbour);
+            desired += separation(position, neighbours, radius.value * SEPARATION_RANGE, max_speed) * SEPARATION_WEIGHT;
+        }
+        desired += avoid(position, velocity.as_vec2(), radius.value, &obstacles, max_speed) * AVOIDANCE_WEIGHT;
+        desired = desired.clamp_length_max(max_speed);
+
+        let change = (desired - velocity.as_vec2()).clamp_length_max(max_speed * ACCELERATION * time.delta_seconds());
+        velocity.x += change.x;
+        velocity.y += change.y;
+    }
+}
@@ -0,0 +1,175 @@
+use std::{mem::size_of, ops::Index, sync::Arc};
+use serde::{Deserialize, Serialize};
+
+use super::history::{Corridor, Snapshot};
+
+const CHUNK_SIZE: usize = 64;
+
+/// Append-only snapshot storage split into fixed size chunks. Full chunks are sealed behind an
+/// `Arc`, so cloning a store (e.g. for a ghost) shares everything but the open tail.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<Snapshot>", into = "Vec<Snapshot>")]
+pub struct SnapshotStore {
+    sealed: Vec<Arc<Vec<Snapshot>>>,
+    start: usize,
+    open: Vec<Snapshot>,
+    corridor: Option<Corridor>,
+}
+
+impl SnapshotStore {
+    pub fn len(&self) -> usize {
+        self.sealed.len() * CHUNK_SIZE - self.start + self.open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Snapshot> {
+        if index >= self.len() {
+            return None;
+        }
+        let absolute = index + self.start;
+        let chunk = absolute / CHUNK_SIZE;
+        if chunk < self.sealed.len() {
+            self.sealed[chunk].get(absolute % CHUNK_SIZE)
+        } else {
+            self.open.get(absolute - self.sealed.len() * CHUNK_SIZE)
+        }
+    }
+
+    pub fn front(&self) -> Option<&Snapshot> {
+        self.get(0)
+    }
+
+    pub fn back(&self) -> Option<&Snapshot> {
+        self.len().checked_sub(1).and_then(|index| self.get(index))
+    }
+
+    pub fn back_mut(&mut self) -> Option<&mut Snapshot> {
+        if let Some(snapshot) = self.open.last_mut() {
+            return Some(snapshot);
+        }
+        self.sealed.last_mut().and_then(|chunk| Arc::make_mut(chunk).last_mut())
+    }
+
+    pub fn push_back(&mut self, snapshot: Snapshot) {
+        self.open.push(snapshot);
+        if self.open.len() == CHUNK_SIZE {
+            let chunk = std::mem::replace(&mut self.open, Vec::with_capacity(CHUNK_SIZE));
+            self.sealed.push(Arc::new(chunk));
+        }
+    }
+
+    pub fn pop_front(&mut self) {
+        if self.sealed.is_empty() {
+            if !self.open.is_empty() {
+                self.open.remove(0);
+            }
+            return;
+        }
+        self.start += 1;
+        if self.start == CHUNK_SIZE {
+            self.sealed.remove(0);
+            self.start = 0;
+        }
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len() {
+            return;
+        }
+        self.corridor = None;
+        let absolute = len + self.start;
+        let chunk = absolute / CHUNK_SIZE;
+        if chunk >= self.sealed.len() {
+            self.open.truncate(absolute - self.sealed.len() * CHUNK_SIZE);
+            return;
+        }
+        let first = if chunk == 0 { self.start } else { 0 };
+        self.open = self.sealed[chunk][first..absolute % CHUNK_SIZE].to_vec();
+        self.sealed.truncate(chunk);
+        if self.sealed.is_empty() {
+            self.start = 0;
+        }
+    }
+
+    /// What the last snapshot may still be folded within, see `history::record`.
+    pub fn corridor(&self) -> Option<Corridor> {
+        self.corridor
+    }
+
+    pub fn set_corridor(&mut self, corridor: Option<Corridor>) {
+        self.corridor = corridor;
+    }
+
+    pub fn clear(&mut self) {
+        *self = SnapshotStore::default();
+    }
+
+    pub fn partition_point(&self, predicate: impl Fn(&Snapshot) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if predicate(&self[middle]) {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Snapshot> {
+        self.sealed.iter()
+            .flat_map(|chunk| chunk.iter())
+            .skip(self.start)
+            .chain(self.open.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Snapshot> {
+        let start = self.start;
+        self.sealed.iter_mut()
+            .flat_map(|chunk| Arc::make_mut(chunk).iter_mut())
+            .skip(start)
+            .chain(self.open.iter_mut())
+    }
+
+    /// Sealed chunks this store holds, used to count shared memory only once.
+    pub fn chunks(&self) -> impl Iterator<Item = &Arc<Vec<Snapshot>>> {
+        self.sealed.iter()
+    }
+
+    /// Bytes owned by the open tail, which is never shared.
+    pub fn open_bytes(&self) -> usize {
+        self.open.capacity() * size_of::<Snapshot>()
+    }
+}
+
+impl Index<usize> for SnapshotStore {
+    type Output = Snapshot;
+
+    fn index(&self, index: usize) -> &Snapshot {
+        self.get(index).expect("snapshot index out of range")
+    }
+}
+
+impl From<Vec<Snapshot>> for SnapshotStore {
+    fn from(snapshots: Vec<Snapshot>) -> Self {
+        let mut store = SnapshotStore::default();
+        for snapshot in snapshots {
+            store.push_back(snapshot);
+        }
+        store
+    }
+}
+
+impl From<SnapshotStore> for Vec<Snapshot> {
+    fn from(store: SnapshotStore) -> Self {
+        store.iter().cloned().collect()
+    }
+}
+
+pub fn chunk_bytes() -> usize {
+    CHUNK_SIZE * size_of::<Snapshot>()
+}
@@ -0,0 +1,134 @@
+use bevy::prelude::*;
+use crate::game::{Round, TimeCursor};
+use crate::input::{CompareBranch, SelectBranch};
+use super::{component, archetype::UnitSpawner, Branch, Timelines, history::sample, recording::{spawn_ghost, RecordedHistory}};
+
+pub fn store_branch(
+    query: Query<(&component::Unit, &component::Kind, &component::History, Option<&component::Enemy>), (Without<component::Ghost>, Without<component::Repeat>, Without<component::Reverse>)>,
+    mut timelines: ResMut<Timelines>,
+    round: Res<Round>,
+    ) {
+    let mut histories = Vec::new();
+    for (unit, kind, history, opt_enemy) in query.iter() {
+        if history.snapshots.is_empty() {
+            continue;
+        }
+        histories.push(RecordedHistory {
+            owner: unit.owner,
+            enemy: opt_enemy.is_some(),
+            archetype: kind.name.clone(),
+            history: history.clone(),
+        });
+    }
+    let parent = timelines.parent;
+    let name = match parent.and_then(|parent| timelines.branches.get(&parent)) {
+        Some(branch) if parent != round.attempts.checked_sub(1) => format!("attempt {} (fork of {})", round.attempts, branch.name),
+        _ => format!("attempt {}", round.attempts),
+    };
+    info!("Stored timeline '{}' with {} histories", name, histories.len());
+    timelines.branches.insert(round.attempts, Branch { name, parent, histories });
+}
+
+pub fn fork(
+    mut spawner: UnitSpawner,
+    survivor_query: Query<Entity, With<component::RespawnNextRound>>,
+    mut timelines: ResMut<Timelines>,
+    ) {
+    let latest = timelines.branches.keys().next_back().copied();
+    let source = timelines.source.take().or(latest);
+    timelines.parent = source;
+    let Some(source) = source else {
+        return;
+    };
+    if Some(source) == latest {
+        // Survivors of the latest attempt replay themselves, see `history::round_repeat`.
+        return;
+    }
+    let Some(branch) = timelines.branches.get(&source) else {
+        return;
+    };
+    info!("Forking from '{}'", branch.name);
+    for entity in survivor_query.iter() {
+        spawner.commands.entity(entity).despawn_recursive();
+    }
+    for recorded in branch.histories.iter() {
+        let survived = recorded.history.snapshots.back().is_some_and(|snapshot| !snapshot.dead);
+        if recorded.enemy || !survived {
+            continue;
+        }
+        let recorded = RecordedHistory {
+            owner: recorded.owner,
+            enemy: recorded.enemy,
+            archetype: recorded.archetype.clone(),
+            history: recorded.history.clone(),
+        };
+        spawn_ghost(&mut spawner, recorded);
+    }
+}
+
+pub fn select_branch(
+    mut select_reader: EventReader<SelectBranch>,
+    mut timelines: ResMut<Timelines>,
+    ) {
+    for event in select_reader.read() {
+        let keys: Vec<u32> = timelines.branches.keys().copied().collect();
+        if keys.is_empty() {
+            warn!("No timelines to fork from yet");
+            continue;
+        }
+        let current = timelines.source
+            .and_then(|source| keys.iter().position(|&key| key == source))
+            .unwrap_or(keys.len() - 1);
+        let index = (current as i32 + event.0).clamp(0, keys.len() as i32 - 1) as usize;
+        timelines.source = Some(keys[index]);
+        if let Some(branch) = timelines.branches.get(&keys[index]) {
+            if let Some(parent) = branch.parent {
+                info!("Next attempt forks from '{}', which was played from attempt {}", branch.name, parent);
+            } else {
+                info!("Next attempt forks from '{}'", branch.name);
+            }
+        }
+    }
+}
+
+pub fn compare_branch(
+    mut compare_reader: EventReader<CompareBranch>,
+    query: Query<(Option<&component::Enemy>, Has<component::Dead>), With<component::Unit>>,
+    timelines: Res<Timelines>,
+    cursor: Res<TimeCursor>,
+    ) {
+    for _ in compare_reader.read() {
+        let source = timelines.source.or(timelines.branches.keys().next_back().copied());
+        let Some(branch) = source.and_then(|source| timelines.branches.get(&source)) else {
+            warn!("No timeline to compare against");
+            continue;
+        };
+        let (mut units, mut enemies) = (0, 0);
+        for (opt_enemy, dead) in query.iter() {
+            if dead {
+                continue;
+            }
+            if let Some(_) = opt_enemy {
+                enemies += 1;
+            } else {
+                units += 1;
+            }
+        }
+        let (mut branch_units, mut branch_enemies) = (0, 0);
+        for recorded in branch.histories.iter() {
+            let Some(snapshot) = sample(&recorded.history.snapshots, cursor.current) else {
+                continue;
+            };
+            if snapshot.dead {
+                continue;
+            }
+            if recorded.enemy {
+                branch_enemies += 1;
+            } else {
+                branch_units += 1;
+            }
+        }
+        info!("At {:.1}s: {} units and {} enemies standing, '{}' had {} units and {} enemies",
+            cursor.current, units, enemies, branch.name, branch_units, branch_enemies);
+    }
+}